@@ -11,7 +11,7 @@ fn set_bench(c: &mut Criterion) {
                 let tmp_dir = TempDir::new().unwrap();
                 (KvStore::open(tmp_dir.path()).unwrap(), tmp_dir)
             },
-            |(mut store, _tmp_dir)| {
+            |(store, _tmp_dir)| {
                 for i in 1..(1 << 10) {
                     let key = format!("key-{}", i);
                     let value = format!("value-{}", i);
@@ -24,7 +24,7 @@ fn set_bench(c: &mut Criterion) {
         b.iter_batched(||{
             let tmp_dir = TempDir::new().unwrap();
             (Sled::open(tmp_dir.path()).unwrap(), tmp_dir)
-        }, |(mut store, _tmp_dir)| {
+        }, |(store, _tmp_dir)| {
             for i in 1..(1<<10) {
                 let key = format!("key-{}", i);
                 let value = format!("value-{}", i);
@@ -38,10 +38,10 @@ fn set_bench(c: &mut Criterion) {
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("kvs_{}", i), i, |b ,i| {
             let tmp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(tmp_dir.path()).unwrap();
+            let store = KvStore::open(tmp_dir.path()).unwrap();
             for key in 1..1<<i {
                 store.set(format!("key{}", key), "value".to_string()).unwrap()
             }
@@ -54,10 +54,10 @@ fn get_bench(c: &mut Criterion) {
         });
     }
 
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{}", i), i, |b ,i| {
             let tmp_dir = TempDir::new().unwrap();
-            let mut store = Sled::open(tmp_dir.path()).unwrap();
+            let store = Sled::open(tmp_dir.path()).unwrap();
             for key in 1..1<<i {
                 store.set(format!("key{}", key), "value".to_string()).unwrap()
             }
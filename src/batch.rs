@@ -0,0 +1,30 @@
+use crate::cmd::Command;
+
+/// Sets and removes that an engine applies all-or-nothing, in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) commands: Vec<Command>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.commands.push(Command::set(key, value));
+    }
+
+    /// Removing a key that does not exist is not an error within a batch.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.commands.push(Command::remove(key));
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
@@ -1,6 +1,7 @@
 use std::net::SocketAddr;
 use std::process::exit;
-use clap::{arg, command, Command};
+use std::time::Duration;
+use clap::{arg, command, value_parser, Command};
 use slog::{Drain, Duplicate, Level, Logger, o};
 use kvs::{debug, error, KvClient, KvError};
 
@@ -39,11 +40,21 @@ fn main() {
             ),
             Command::new("set").args([
                 arg!(<key>).required(true),
-                arg!(<value>).required(true)
+                arg!(<value>).required(true),
+                arg!(--ttl <SECONDS> "Remove the key after this many seconds")
+                    .value_parser(value_parser!(u64))
             ]),
             Command::new("rm").arg(
                 arg!(<key>).required(true)
-            )
+            ),
+            Command::new("checkpoint")
+                .about("Write a consistent copy of the server's store")
+                .arg(
+                    arg!(<name> "Directory to create within the server's --checkpoint-dir")
+                        .required(true)
+                ),
+            Command::new("stats")
+                .about("Show how much the server's store holds")
         ])
         .get_matches();
     let addr: SocketAddr = matches.get_one::<String>("addr")
@@ -82,7 +93,8 @@ fn main() {
         Some(("set", arg_matches)) => {
             let key = arg_matches.get_one::<String>("key").unwrap();
             let value = arg_matches.get_one::<String>("value").unwrap();
-            debug!(logger, "set {} {} {}", addr, key, value);
+            let ttl = arg_matches.get_one::<u64>("ttl");
+            debug!(logger, "set {} {} {} {:?}", addr, key, value, ttl);
 
             let kv_client = KvClient::connect(addr);
             if let Err(e) = kv_client {
@@ -90,12 +102,14 @@ fn main() {
                 exit(1);
             }
 
-            match kv_client.unwrap().set(key.to_string(), value.to_string()) {
-                Err(e) => {
-                    error!(logger, "{}", e);
-                    exit(1);
-                }
-                _ => {}
+            let mut kv_client = kv_client.unwrap();
+            let result = match ttl {
+                Some(&ttl) => kv_client.set_with_ttl(key.to_string(), value.to_string(), Duration::from_secs(ttl)),
+                None => kv_client.set(key.to_string(), value.to_string()),
+            };
+            if let Err(e) = result {
+                error!(logger, "{}", e);
+                exit(1);
             }
         }
         Some(("rm", arg_matches)) => {
@@ -108,20 +122,51 @@ fn main() {
                 exit(1);
             }
 
-            match kv_client.unwrap().remove(key.to_string()) {
-                Err(e) => {
-                    match e {
-                        KvError::KeyNotFound => {
-                            eprintln!("Key not found");
-                            exit(1);
-                        }
-                        _ => {
-                            error!(logger, "{}", e);
-                            exit(1);
-                        }
+            if let Err(e) = kv_client.unwrap().remove(key.to_string()) {
+                match e {
+                    KvError::KeyNotFound => {
+                        eprintln!("Key not found");
+                        exit(1);
+                    }
+                    _ => {
+                        error!(logger, "{}", e);
+                        exit(1);
                     }
                 }
-                _ => {}
+            }
+        }
+        Some(("checkpoint", arg_matches)) => {
+            let name = arg_matches.get_one::<String>("name").unwrap();
+            debug!(logger, "checkpoint {} {}", addr, name);
+
+            let kv_client = KvClient::connect(addr);
+            if let Err(e) = kv_client {
+                error!(logger, "{:?}", e);
+                exit(1);
+            }
+
+            // Printed right away, the logger would lose it on exit.
+            if let Err(e) = kv_client.unwrap().checkpoint(name.to_string()) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Some(("stats", _)) => {
+            debug!(logger, "stats {}", addr);
+
+            let kv_client = KvClient::connect(addr);
+            if let Err(e) = kv_client {
+                error!(logger, "{:?}", e);
+                exit(1);
+            }
+
+            match kv_client.unwrap().stats() {
+                Ok(stats) => println!("{}", stats),
+                // Printed right away, the logger would lose it on exit.
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
             }
         }
         _ => {
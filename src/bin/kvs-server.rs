@@ -1,8 +1,9 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
-use clap::{arg, command};
+use clap::{arg, command, value_parser};
 use slog::{Drain, Duplicate, Level, Logger, o};
-use kvs::{error, info, KvServer};
+use kvs::{error, info, Durability, KvServer};
 
 fn main() {
     let stdout_decorator = slog_term::TermDecorator::new()
@@ -34,6 +35,10 @@ fn main() {
             [
                 arg!(--addr <Value>).global(true),
                 arg!(--engine <Value>).global(true),
+                arg!(--durability <Value> "buffered, flush, fsync or group-commit[:<millis>]").global(true),
+                arg!(--"checkpoint-dir" <DIR> "Where clients may write checkpoints, they cannot without it")
+                    .value_parser(value_parser!(PathBuf))
+                    .global(true),
             ]
         )
         .get_matches();
@@ -43,7 +48,9 @@ fn main() {
         .unwrap();
 
     let engine_type_str = matches.get_one::<String>("engine");
-    info!(logger, "version: {}, address: {}, engine: {:?}", env!("CARGO_PKG_VERSION"), addr, engine_type_str);
+    let durability_str = matches.get_one::<String>("durability");
+    info!(logger, "version: {}, address: {}, engine: {:?}, durability: {:?}",
+        env!("CARGO_PKG_VERSION"), addr, engine_type_str, durability_str);
 
     let engine_type = engine_type_str.unwrap_or(&"auto".to_string()).parse();
     if let Err(e) = engine_type {
@@ -51,13 +58,23 @@ fn main() {
         exit(1);
     }
 
-    let kv_server = KvServer::new(logger.clone(), engine_type.unwrap());
+    let durability = durability_str.map_or(Ok(Durability::default()), |s| s.parse());
+    if let Err(e) = durability {
+        error!(logger, "{}", e);
+        exit(1);
+    }
+
+    let kv_server = KvServer::new(logger.clone(), engine_type.unwrap(), durability.unwrap());
     if let Err(e) = kv_server {
         error!(logger, "{}", e);
         exit(1);
     }
 
-    if let Err(e) = kv_server.unwrap().start(addr.clone()) {
+    let mut kv_server = kv_server.unwrap();
+    if let Some(dir) = matches.get_one::<PathBuf>("checkpoint-dir") {
+        kv_server = kv_server.checkpoint_dir(dir.clone());
+    }
+    if let Err(e) = kv_server.start(addr) {
         error!(logger, "{}", e);
         exit(1);
     }
@@ -1,7 +1,9 @@
-use clap::{arg, command, Command};
-use kvs::{KvError, KvsEngine, KvStore};
+use clap::{arg, command, value_parser, Command};
+use kvs::{KvError, KvsEngine, KvStore, KvStoreOptions, RestorePoint, Sled};
 use std::env;
 use std::env::current_dir;
+use std::{fs, io};
+use std::path::PathBuf;
 use std::process::exit;
 use std::string::String;
 
@@ -17,11 +19,45 @@ fn main() -> kvs::Result<()> {
                 .arg(arg!([value]).required(true)),
         )
         .subcommand(Command::new("rm").arg(arg!([key])))
+        .subcommand(
+            Command::new("dump")
+                .about("Write every key/value pair to stdout as JSON Lines")
+                .arg(engine_arg()),
+        )
+        .subcommand(
+            Command::new("load")
+                .about("Set every key/value pair of a dump read from stdin")
+                .arg(engine_arg()),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check the data directory for corrupt records and stray files")
+        )
+        .subcommand(
+            Command::new("repair")
+                .about("Salvage the readable records of damaged logs and quarantine the originals")
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Rebuild a data directory from a checkpoint and archived log segments")
+                .arg(arg!(<checkpoint>).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(<archive>).value_parser(value_parser!(PathBuf)))
+                .arg(arg!(<target>).value_parser(value_parser!(PathBuf)))
+                .arg(
+                    arg!(--seq <SEQ> "Stop after the write with this sequence number")
+                        .value_parser(value_parser!(u64))
+                        .conflicts_with("time"),
+                )
+                .arg(
+                    arg!(--time <MILLIS> "Stop after the writes made by this time, in milliseconds since the Unix epoch")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
         .get_matches();
     match matches.subcommand() {
         Some(("get", sub_matches)) => {
             let key = sub_matches.get_one::<String>("key").unwrap().to_string();
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = KvStore::open_with(current_dir()?, KvStoreOptions::new().read_only(true))?;
             match store.get(key)? {
                 Some(value) => {
                     println!("{}", value)
@@ -32,12 +68,12 @@ fn main() -> kvs::Result<()> {
         Some(("set", sub_matches)) => {
             let key = sub_matches.get_one::<String>("key").unwrap().to_string();
             let value = sub_matches.get_one::<String>("value").unwrap().to_string();
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = KvStore::open(current_dir()?)?;
             store.set(key, value)?;
         }
         Some(("rm", sub_matches)) => {
             let key = sub_matches.get_one::<String>("key").unwrap().to_string();
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = KvStore::open(current_dir()?)?;
             match store.remove(key) {
                 Ok(())=>{},
                 Err(KvError::KeyNotFound) => {
@@ -49,8 +85,100 @@ fn main() -> kvs::Result<()> {
                 }
             }
         }
+        Some(("dump", sub_matches)) => {
+            let engine = open_engine(sub_matches.get_one::<String>("engine"), true)?;
+            engine.dump(&mut io::stdout().lock())?;
+        }
+        Some(("load", sub_matches)) => {
+            let engine = open_engine(sub_matches.get_one::<String>("engine"), false)?;
+            let count = engine.load(&mut io::stdin().lock())?;
+            eprintln!("Loaded {} keys", count);
+        }
+        Some(("verify", _)) => {
+            let report = KvStore::verify(&current_dir()?)?;
+            for generation in &report.generations {
+                println!("{}", generation);
+            }
+            for problem in &report.problems {
+                println!("problem: {}", problem);
+            }
+            if !report.is_ok() {
+                eprintln!("{} problems found", report.problems.len());
+                exit(1);
+            }
+        }
+        Some(("repair", _)) => {
+            let report = KvStore::repair(&current_dir()?)?;
+            if report.is_clean() {
+                println!("Nothing to repair");
+            }
+            for gen in &report.quarantined {
+                println!("quarantined gen {}", gen);
+            }
+            if let Some(output) = report.output {
+                println!("salvaged {} records into gen {}", report.salvaged_records, output);
+            }
+            for range in &report.lost {
+                println!("{}", range);
+            }
+            for key in &report.lost_blobs {
+                println!("lost blob of key {:?}", String::from_utf8_lossy(key));
+            }
+        }
+        Some(("restore", sub_matches)) => {
+            let point = match (sub_matches.get_one::<u64>("seq"), sub_matches.get_one::<u64>("time")) {
+                (Some(&seq), _) => RestorePoint::Seq(seq),
+                (_, Some(&time)) => RestorePoint::Time(time),
+                _ => RestorePoint::Latest,
+            };
+            KvStore::restore(
+                sub_matches.get_one::<PathBuf>("checkpoint").unwrap(),
+                sub_matches.get_one::<PathBuf>("archive").unwrap(),
+                sub_matches.get_one::<PathBuf>("target").unwrap(),
+                point,
+            )?;
+        }
         _ => unreachable!(),
     }
 
     Ok(())
 }
+
+fn engine_arg() -> clap::Arg {
+    arg!(--engine <ENGINE> "Engine of the data directory, by default the one it was written with")
+        .value_parser(["kvs", "sled"])
+}
+
+// Like kvs-server, refuses to open a directory with another engine than the one its `engine`
+// marker names, and marks a directory it writes to.
+fn open_engine(engine: Option<&String>, read_only: bool) -> kvs::Result<Box<dyn KvsEngine>> {
+    let dir = current_dir()?;
+    let marker = match fs::read_to_string(dir.join("engine")) {
+        Ok(marker) => Some(marker.trim().to_owned()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let engine = match (engine, marker) {
+        (Some(engine), Some(marker)) if *engine != marker => {
+            eprintln!("engine mismatch: the data directory was written with {}", marker);
+            exit(1);
+        }
+        (_, Some(marker)) => marker,
+        (engine, None) => {
+            let engine = engine.map_or("kvs", String::as_str).to_owned();
+            if !read_only {
+                fs::create_dir_all(&dir)?;
+                fs::write(dir.join("engine"), &engine)?;
+            }
+            engine
+        }
+    };
+    match engine.as_str() {
+        "sled" => Ok(Box::new(Sled::open(dir)?)),
+        "kvs" => Ok(Box::new(KvStore::open_with(dir, KvStoreOptions::new().read_only(read_only))?)),
+        _ => {
+            eprintln!("unknown engine {} in the data directory", engine);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crate::err::{KvError, Result};
+
+// Values over `KvStoreOptions::blob_threshold` are kept out of the log, so compaction only
+// copies a small pointer record for them. A blob file is named after the sequence number of
+// the record pointing at it, which compaction keeps, and holds the value behind a checksum.
+const BLOB_MAGIC: [u8; 4] = *b"RKVB";
+const BLOB_HEADER_SIZE: usize = 8;
+
+pub fn blob_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{}.blob", seq))
+}
+
+// The blob is written before the record pointing at it, so a blob cut short by a crash is
+// never referenced and gets deleted on open.
+pub fn write_blob_file(dir: &Path, seq: u64, value: &[u8], sync: bool) -> Result<()> {
+    let mut file = File::create(blob_path(dir, seq))?;
+    file.write_all(&BLOB_MAGIC)?;
+    file.write_all(&crc32fast::hash(value).to_be_bytes())?;
+    file.write_all(value)?;
+    if sync {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+pub fn read_blob_file(dir: &Path, seq: u64) -> Result<Vec<u8>> {
+    let mut content = fs::read(blob_path(dir, seq))?;
+    if content.len() < BLOB_HEADER_SIZE || content[..4] != BLOB_MAGIC {
+        return Err(KvError::ChecksumMismatch);
+    }
+
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&content[4..8]);
+    let value = content.split_off(BLOB_HEADER_SIZE);
+    if crc32fast::hash(&value) != u32::from_be_bytes(crc) {
+        return Err(KvError::ChecksumMismatch);
+    }
+    Ok(value)
+}
+
+pub fn remove_blob_file(dir: &Path, seq: u64) -> Result<()> {
+    match fs::remove_file(blob_path(dir, seq)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// Sequence numbers of every blob file in `dir`.
+pub fn list_blob_files(dir: &Path) -> Result<Vec<u64>> {
+    let mut seqs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("blob".as_ref()) {
+            if let Some(seq) = path.file_stem().and_then(OsStr::to_str).and_then(|s| s.parse().ok()) {
+                seqs.push(seq);
+            }
+        }
+    }
+    Ok(seqs)
+}
@@ -0,0 +1,87 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+/// Counters of the `KvStore` value cache, all zero for `Sled`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Bytes of keys and values currently cached.
+    pub size: u64,
+}
+
+// Values by key, each tagged with the version it was read at, so a lookup only hits while the
+// index still points at that version. The least recently used values are evicted once the
+// cached keys and values take more than `capacity` bytes.
+pub(crate) struct ValueCache {
+    capacity: u64,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entries {
+    lru: LruCache<Vec<u8>, (u64, Vec<u8>)>,
+    size: u64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            capacity,
+            entries: Mutex::new(Entries {
+                lru: LruCache::unbounded(),
+                size: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &[u8], version: u64) -> Option<Vec<u8>> {
+        let value = match self.entries.lock().unwrap().lru.get(key) {
+            Some((cached_version, value)) if *cached_version == version => Some(value.clone()),
+            _ => None,
+        };
+
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub fn insert(&self, key: Vec<u8>, version: u64, value: Vec<u8>) {
+        let size = (key.len() + value.len()) as u64;
+        if size > self.capacity {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((old_key, (_, old_value))) = entries.lru.push(key, (version, value)) {
+            entries.size -= (old_key.len() + old_value.len()) as u64;
+        }
+        entries.size += size;
+        while entries.size > self.capacity {
+            match entries.lru.pop_lru() {
+                Some((key, (_, value))) => entries.size -= (key.len() + value.len()) as u64,
+                None => break,
+            }
+        }
+    }
+
+    pub fn invalidate(&self, key: &[u8]) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((_, value)) = entries.lru.pop(key) {
+            entries.size -= (key.len() + value.len()) as u64;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.entries.lock().unwrap().size,
+        }
+    }
+}
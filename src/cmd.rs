@@ -2,16 +2,66 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+    // A write batch is logged as `BatchBegin`, its `count` commands and `BatchCommit`.
+    // New variants must go last, bincode identifies them by position.
+    BatchBegin { count: u64 },
+    BatchCommit,
+    // `expires_at` is in milliseconds since the Unix epoch.
+    SetWithTtl { key: Vec<u8>, value: Vec<u8>, expires_at: u64 },
+    // Written by compaction with the last sequence number handed out before it started, so
+    // versions stay monotonic after the records that used them are gone.
+    SeqMark,
+    // A set whose value of `len` bytes is in the blob file named after the record's sequence
+    // number.
+    SetBlob { key: Vec<u8>, len: u64, expires_at: Option<u64> },
 }
 
 impl Command {
-    pub fn set(key: String, value: String) -> Command {
+    pub fn set(key: Vec<u8>, value: Vec<u8>) -> Command {
         Command::Set { key, value }
     }
 
-    pub fn remove(key: String) -> Command {
+    pub fn remove(key: Vec<u8>) -> Command {
         Command::Remove { key }
     }
+
+    pub fn set_with_ttl(key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> Command {
+        Command::SetWithTtl { key, value, expires_at }
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            Command::Set { key, .. }
+            | Command::Remove { key }
+            | Command::SetWithTtl { key, .. }
+            | Command::SetBlob { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::SetWithTtl { expires_at, .. } => Some(*expires_at),
+            Command::SetBlob { expires_at, .. } => *expires_at,
+            _ => None,
+        }
+    }
+}
+
+// Commands as written by the JSON log format, which only supported UTF-8 keys and values.
+#[derive(Deserialize, Debug)]
+pub enum JsonCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<JsonCommand> for Command {
+    fn from(cmd: JsonCommand) -> Command {
+        match cmd {
+            JsonCommand::Set { key, value } => Command::set(key.into_bytes(), value.into_bytes()),
+            JsonCommand::Remove { key } => Command::remove(key.into_bytes()),
+        }
+    }
 }
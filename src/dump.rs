@@ -0,0 +1,90 @@
+use std::io::{BufRead, Write};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use crate::err::{KvError, Result};
+
+// A dump is a JSON Lines file with one live pair per line, the same for every engine:
+//
+//     {"key":"user1","value":"alice"}
+//     {"key":"AAE=","value":"/w==","encoding":"base64","expires_at":1712000000000}
+//
+// Keys and values are written as strings when both are UTF-8, and in base64 otherwise.
+// `expires_at` is the expiry deadline in milliseconds since the Unix epoch.
+#[derive(Serialize, Deserialize)]
+struct DumpLine {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+impl Encoding {
+    fn is_utf8(&self) -> bool {
+        *self == Encoding::Utf8
+    }
+}
+
+/// A live pair as `KvsEngine::dump` writes it and `KvsEngine::load` reads it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpEntry {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// Expiry deadline in milliseconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+}
+
+pub type DumpIter = Box<dyn Iterator<Item = Result<DumpEntry>> + Send>;
+
+pub(crate) fn write_entry(writer: &mut dyn Write, entry: DumpEntry) -> Result<()> {
+    let line = match (String::from_utf8(entry.key), String::from_utf8(entry.value)) {
+        (Ok(key), Ok(value)) => DumpLine {
+            key,
+            value,
+            encoding: Encoding::Utf8,
+            expires_at: entry.expires_at,
+        },
+        (key, value) => DumpLine {
+            key: STANDARD.encode(key.map_or_else(|e| e.into_bytes(), String::into_bytes)),
+            value: STANDARD.encode(value.map_or_else(|e| e.into_bytes(), String::into_bytes)),
+            encoding: Encoding::Base64,
+            expires_at: entry.expires_at,
+        },
+    };
+    serde_json::to_writer(&mut *writer, &line)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+// Blank lines are skipped. Errors name the line they were found on, counting from 1.
+pub(crate) fn read_entries(reader: &mut dyn BufRead) -> impl Iterator<Item = Result<DumpEntry>> + '_ {
+    reader.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(number, line)| {
+            let invalid = |message: String| KvError::InvalidDump { line: number as u64 + 1, message };
+            let line: DumpLine = serde_json::from_str(&line?).map_err(|e| invalid(e.to_string()))?;
+            let (key, value) = match line.encoding {
+                Encoding::Utf8 => (line.key.into_bytes(), line.value.into_bytes()),
+                Encoding::Base64 => (
+                    STANDARD.decode(line.key).map_err(|e| invalid(e.to_string()))?,
+                    STANDARD.decode(line.value).map_err(|e| invalid(e.to_string()))?,
+                ),
+            };
+            Ok(DumpEntry {
+                key,
+                value,
+                expires_at: line.expires_at,
+            })
+        })
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+use crate::err::Result;
+
+const DEFAULT_GROUP_COMMIT_WINDOW: Duration = Duration::from_millis(2);
+
+/// How far a write has to reach before `set` and friends return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Writes stay in the engine's buffers. `KvStore` only hands them to the operating system
+    /// once its buffer fills, on a later flush or when it is closed; sled syncs in the background.
+    Buffered,
+    /// Every write is handed to the operating system. Survives a crash of the process,
+    /// but not a power failure.
+    #[default]
+    Flush,
+    /// Every write is synced to the disk before it is acknowledged.
+    Fsync,
+    /// Writes are synced to the disk before they are acknowledged, with a single sync for all
+    /// the writes that arrive within `window` of each other.
+    GroupCommit { window: Duration },
+}
+
+#[derive(Error, Debug)]
+pub struct ParseDurabilityError;
+
+impl Display for ParseDurabilityError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "invalid durability, expected buffered, flush, fsync or group-commit[:<millis>]")
+    }
+}
+
+impl FromStr for Durability {
+    type Err = ParseDurabilityError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "buffered" => Ok(Durability::Buffered),
+            "flush" => Ok(Durability::Flush),
+            "fsync" => Ok(Durability::Fsync),
+            "group-commit" => Ok(Durability::GroupCommit { window: DEFAULT_GROUP_COMMIT_WINDOW }),
+            _ => match s.strip_prefix("group-commit:").map(str::parse::<u64>) {
+                Some(Ok(millis)) => Ok(Durability::GroupCommit { window: Duration::from_millis(millis) }),
+                _ => Err(ParseDurabilityError),
+            },
+        }
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Buffered => write!(f, "buffered"),
+            Durability::Flush => write!(f, "flush"),
+            Durability::Fsync => write!(f, "fsync"),
+            Durability::GroupCommit { window } => write!(f, "group-commit:{}", window.as_millis()),
+        }
+    }
+}
+
+// Writers wait here for their write, identified by an increasing ticket, to be synced. The
+// first writer to arrive leads: it waits `window` for others to join, syncs once for all of
+// them and wakes them up. When a sync fails, the next waiter leads another attempt.
+pub(crate) struct GroupCommit {
+    window: Duration,
+    state: Mutex<GroupState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct GroupState {
+    synced: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    pub fn new(window: Duration) -> GroupCommit {
+        GroupCommit {
+            window,
+            state: Mutex::new(GroupState::default()),
+            synced: Condvar::new(),
+        }
+    }
+
+    // `sync` returns the highest ticket it made durable.
+    pub fn commit(&self, ticket: u64, sync: impl FnOnce() -> Result<u64>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.synced.wait(state).unwrap();
+        }
+        state.syncing = true;
+        drop(state);
+
+        thread::sleep(self.window);
+        let result = sync();
+
+        let mut state = self.state.lock().unwrap();
+        state.syncing = false;
+        if let Ok(synced) = result {
+            state.synced = state.synced.max(synced);
+        }
+        self.synced.notify_all();
+        result.map(|_| ())
+    }
+}
@@ -1,5 +1,6 @@
 use std::{io, result};
 use std::str::Utf8Error;
+use sled::transaction::TransactionError;
 use thiserror::Error;
 use crate::net::MsgError;
 
@@ -26,10 +27,49 @@ pub enum KvError {
     #[error("Key not found")]
     KeyNotFound,
 
+    #[error("Condition failed")]
+    ConditionFailed,
+
+    #[error("Data directory is locked by another process")]
+    Locked,
+
+    #[error("Store is opened read-only")]
+    ReadOnly,
+
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("Corrupted log header")]
+    CorruptedLogHeader,
+
+    #[error("Unsupported log version {0}")]
+    UnsupportedLogVersion(u32),
+
+    #[error("Invalid dump line {line}: {message}")]
+    InvalidDump { line: u64, message: String },
+
+    /// A request the server turned down, with the reason it gave.
+    #[error("Server error: {0}")]
+    Server(String),
+
     #[error("Unknown")]
     Unknown,
 }
 
+impl KvError {
+    // Errors caused by the content of a log rather than by the file system.
+    pub(crate) fn is_corruption(&self) -> bool {
+        match self {
+            KvError::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+            KvError::SerdeJson(_)
+            | KvError::SerdeBinary(_)
+            | KvError::ChecksumMismatch
+            | KvError::CorruptedLogHeader => true,
+            _ => false,
+        }
+    }
+}
+
 impl From<MsgError> for KvError {
     fn from(value: MsgError) -> Self {
         match value {
@@ -39,4 +79,13 @@ impl From<MsgError> for KvError {
     }
 }
 
+impl From<TransactionError> for KvError {
+    fn from(value: TransactionError) -> Self {
+        match value {
+            TransactionError::Storage(e) => KvError::Sled(e),
+            TransactionError::Abort(e) => KvError::Sled(e),
+        }
+    }
+}
+
 pub type Result<Value> = result::Result<Value, KvError>;
\ No newline at end of file
@@ -0,0 +1,93 @@
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::err::Result;
+
+// A hint file describes every record of a sealed generation, so the index can be rebuilt
+// without reading the values back from the log. It records the length of the log it was
+// built from and is ignored once the log no longer has that length.
+const HINT_MAGIC: [u8; 4] = *b"RKVH";
+const HINT_VERSION: u32 = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum HintEntry {
+    // `blob_len` is set when the value is in a blob file.
+    Set { key: Vec<u8>, gen: u64, pos: u64, size: u64, seq: u64, expires_at: Option<u64>, blob_len: Option<u64> },
+    Remove { key: Vec<u8>, size: u64, seq: u64 },
+    SeqMark { seq: u64 },
+}
+
+impl HintEntry {
+    pub fn seq(&self) -> u64 {
+        match self {
+            HintEntry::Set { seq, .. } | HintEntry::Remove { seq, .. } | HintEntry::SeqMark { seq } => *seq,
+        }
+    }
+}
+
+pub fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+pub fn write_hint_file(dir: &Path, gen: u64, log_len: u64, entries: &[HintEntry]) -> Result<()> {
+    let payload = bincode::serialize(entries)?;
+    let tmp_path = dir.join(format!("{}.hint.tmp", gen));
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&log_len.to_be_bytes());
+    hasher.update(&payload);
+
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&HINT_MAGIC)?;
+    writer.write_all(&HINT_VERSION.to_be_bytes())?;
+    writer.write_all(&log_len.to_be_bytes())?;
+    writer.write_all(&(payload.len() as u64).to_be_bytes())?;
+    writer.write_all(&hasher.finalize().to_be_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    fs::rename(tmp_path, hint_path(dir, gen))?;
+    Ok(())
+}
+
+// Returns `None` when the generation has no hint file or the hint file cannot be trusted.
+pub fn read_hint_file(dir: &Path, gen: u64, log_len: u64) -> Result<Option<Vec<HintEntry>>> {
+    let mut content = Vec::new();
+    match File::open(hint_path(dir, gen)) {
+        Ok(mut file) => file.read_to_end(&mut content)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if content.len() < 28 || content[..4] != HINT_MAGIC || content[4..8] != HINT_VERSION.to_be_bytes() {
+        return Ok(None);
+    }
+    if content[8..16] != log_len.to_be_bytes() {
+        return Ok(None);
+    }
+
+    let mut len = [0; 8];
+    len.copy_from_slice(&content[16..24]);
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&content[24..28]);
+
+    let payload = &content[28..];
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&content[8..16]);
+    hasher.update(payload);
+    if payload.len() as u64 != u64::from_be_bytes(len) || hasher.finalize() != u32::from_be_bytes(crc) {
+        return Ok(None);
+    }
+
+    Ok(bincode::deserialize(payload).ok())
+}
+
+pub fn remove_hint_file(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -1,186 +1,1995 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
 use std::ffi::OsStr;
 use std::{fs, io};
-use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write, Read};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use serde_json::Deserializer;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use ::log::warn;
+use crate::batch::WriteBatch;
 use crate::cmd::Command;
 use crate::err::KvError;
 use crate::err::Result;
+use crate::hint::{hint_path, HintEntry, read_hint_file, remove_hint_file, write_hint_file};
+use crate::cache::{CacheStats, ValueCache};
+use crate::manifest::{CompactionRecord, Manifest, read_manifest, write_manifest};
+use crate::blob::{blob_path, list_blob_files, read_blob_file, remove_blob_file, write_blob_file};
+use crate::dump::{DumpEntry, DumpIter};
+use crate::engine::{create_checkpoint_dir, is_empty_range, KeyRange, ScanIter, ScanOptions};
 use crate::KvsEngine;
-use crate::stream::{BufReaderWithPos, BufWriterWithPos};
+use crate::durability::{Durability, GroupCommit};
+use crate::options::{KvStoreOptions, RecoveryMode};
+use crate::snapshot::KvSnapshot;
+use crate::record::{LOG_HEADER_SIZE, LogEntry, LogReader, salvage_log, Salvaged, write_log_header, write_record};
+use crate::restore::RestorePoint;
+use crate::verify::{GenerationReport, Problem, VerifyReport};
+use crate::repair::{LostRange, RepairReport};
+use crate::stats::{CompactionStats, EngineStats};
+use crate::stream::BufWriterWithPos;
+use crate::ttl::{deadline, is_expired, now_millis, Reaper};
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const SCAN_BATCH_SIZE: usize = 128;
+const QUARANTINE_DIR: &str = "quarantine";
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct CommandPosition {
     log_start_pos: u64,
     size: u64,
     gen: u64,
+    seq: u64,
+    expires_at: Option<u64>,
+    blob_len: Option<u64>,
 }
 
+impl CommandPosition {
+    // Bytes the value takes up on disk, its blob included.
+    fn data_size(&self) -> u64 {
+        self.size + self.blob_len.unwrap_or_default()
+    }
+}
+
+pub(crate) type Index = BTreeMap<Vec<u8>, CommandPosition>;
+
+// A read-only store has no writer and no active generation.
+#[derive(Clone)]
 pub struct KvStore {
-    dir: PathBuf,
+    index: Arc<RwLock<Index>>,
+    readers: Arc<ReaderPool>,
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    log: Option<Arc<ActiveLog>>,
+    group_commit: Option<Arc<GroupCommit>>,
+    generations: Arc<Generations>,
+    _reaper: Option<Arc<Reaper>>,
+    // Dropped last, so the directory stays locked until the writer has sealed its generation.
+    _lock: Arc<Option<File>>,
+}
+
+// The log file of the active generation. With `Durability::Buffered` the index can point at
+// records that are still buffered, so readers flush it themselves when they need one. It is
+// never locked by anyone waiting for the index lock.
+struct ActiveLog {
+    file: Mutex<ActiveFile>,
+    // Every record with a lower sequence number has been handed to the operating system.
+    flushed_seq: AtomicU64,
+}
+
+struct ActiveFile {
     writer: BufWriterWithPos<File>,
-    readers: HashMap<u64, BufReaderWithPos<File>>,
-    index: BTreeMap<String, CommandPosition>,
+    end_seq: u64,
+}
+
+// Idle per-thread sets of file handles. A reader checks one out for the duration of a read,
+// so concurrent gets never share a handle or wait on each other. The pool of a snapshot also
+// holds its pin, so the generations stay on disk as long as anything can read through it.
+pub(crate) struct ReaderPool {
+    dir: Arc<PathBuf>,
+    active: Option<Arc<ActiveLog>>,
+    // Only the store's own pool drops the handles of generations that are no longer live, a
+    // snapshot keeps reading from them.
+    generations: Option<Arc<Generations>>,
+    // Each set remembers the `Generations::epoch` its handles were last checked against.
+    idle: Mutex<Vec<(u64, HashMap<u64, LogReader>)>>,
+    cache: Option<Arc<ValueCache>>,
+    _pin: Option<GenerationPin>,
+}
+
+// Decides when compaction may delete the generations it replaced. Generations that a snapshot
+// still reads from are only retired, renamed out of the way and deleted once the last snapshot
+// pinning them is gone. A retired generation left behind by a crash is deleted on open.
+struct Generations {
+    dir: Arc<PathBuf>,
+    // Bumped every time compaction replaces generations.
+    epoch: AtomicU64,
+    pins: Mutex<Pins>,
+    blobs: Mutex<BlobRefs>,
+    // Kept in sync with the MANIFEST file, which is rewritten on every change. Read-only
+    // stores never write it.
+    manifest: Mutex<Manifest>,
+}
+
+#[derive(Default)]
+struct Pins {
+    refs: HashMap<u64, usize>,
+    retired: HashSet<u64>,
+}
+
+// Blob files are garbage collected along with the generations: a blob is deleted once no
+// generation on disk, retired ones included, holds a record pointing at it.
+#[derive(Default)]
+struct BlobRefs {
+    gens: HashMap<u64, Vec<u64>>,
+    // Number of generations referring to each blob, and its length.
+    refs: HashMap<u64, (usize, u64)>,
+}
+
+pub(crate) struct GenerationPin {
+    generations: Arc<Generations>,
+    gens: BTreeSet<u64>,
+}
+
+struct KvStoreWriter {
+    dir: Arc<PathBuf>,
+    options: KvStoreOptions,
+    log: Arc<ActiveLog>,
+    index: Arc<RwLock<Index>>,
     stale_data_size: u64,
+    live_data_size: u64,
+    gen_count: usize,
     current_gen: u64,
+    next_seq: u64,
+    generations: Arc<Generations>,
+    cache: Option<Arc<ValueCache>>,
+    compaction: Option<Compaction>,
+    last_compaction: Option<CompactionStats>,
+    active_hints: Vec<HintEntry>,
+    // Keys with a TTL, ordered by their deadline.
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+}
+
+// The handle is taken out by `KvStore::compact` while it waits for the compaction to finish.
+struct Compaction {
+    handle: Option<JoinHandle<Result<()>>>,
+    started: Instant,
+}
+
+impl KvStore {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(dir, KvStoreOptions::default())
+    }
+
+    pub fn open_with_recovery(dir: impl Into<PathBuf>, recovery_mode: RecoveryMode) -> Result<KvStore> {
+        KvStore::open_with(dir, KvStoreOptions::new().recovery_mode(recovery_mode))
+    }
+
+    pub fn open_with(dir: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let dir = Arc::new(dir.into());
+        let read_only = options.read_only;
+        if !read_only {
+            fs::create_dir_all(dir.as_path())?;
+        }
+        let lock = lock_dir(&dir, read_only)?;
+        if let (Some(archive), false) = (&options.archive_dir, read_only) {
+            fs::create_dir_all(archive)?;
+        }
+
+        let mut index: Index = BTreeMap::new();
+        let mut readers: HashMap<u64, LogReader> = HashMap::new();
+        if !read_only {
+            remove_retired_generations(&dir)?;
+        }
+        let mut manifest = match read_manifest(&dir)? {
+            Some(manifest) => recover_manifest(&dir, manifest, read_only)?,
+            None => Manifest {
+                generations: get_sorted_gens(&dir)?.into_iter().collect(),
+                compaction: None,
+            },
+        };
+        // A generation is listed before its log is created.
+        let gens: Vec<u64> = manifest.generations.iter()
+            .copied()
+            .filter(|&gen| log_path(&dir, gen).exists())
+            .collect();
+        manifest.generations = gens.iter().copied().collect();
+
+        let mut generations = Vec::with_capacity(gens.len());
+        let mut next_seq = 0;
+        for &gen in &gens {
+            let file_path = log_path(&dir, gen);
+            let hints = match read_hint_file(&dir, gen, fs::metadata(&file_path)?.len())? {
+                Some(hints) => hints,
+                None => {
+                    let recover = options.recovery_mode == RecoveryMode::TruncateTail && gens.last() == Some(&gen);
+                    if recover && !read_only {
+                        recover_log_tail(&file_path)?;
+                    }
+
+                    // A read-only store leaves a torn tail on disk and stops reading before it.
+                    let mut reader = match LogReader::open(&file_path) {
+                        Err(e) if recover && read_only && e.is_corruption() => {
+                            warn!("ignoring {} with a torn header", file_path.display());
+                            generations.push((gen, Vec::new()));
+                            continue;
+                        }
+                        result => result?,
+                    };
+                    let mut hints = Vec::new();
+                    match replay_into(gen, &mut reader, next_seq, &mut hints) {
+                        Err(e) if recover && read_only && e.is_corruption() => {
+                            warn!("ignoring torn or corrupted records at the end of {}", file_path.display());
+                        }
+                        result => result?,
+                    }
+
+                    // Every generation found on open is sealed, so its hints stay valid.
+                    if !read_only {
+                        write_hint_file(&dir, gen, fs::metadata(&file_path)?.len(), &hints)?;
+                    }
+                    readers.insert(gen, reader);
+                    hints
+                }
+            };
+            next_seq = hints.iter().map(|hint| hint.seq() + 1).fold(next_seq, u64::max);
+            generations.push((gen, hints));
+        }
+
+        let mut blobs = BlobRefs::default();
+        for (gen, hints) in &generations {
+            for hint in hints {
+                if let HintEntry::Set { seq, blob_len: Some(len), .. } = hint {
+                    blobs.add(*gen, *seq, *len);
+                }
+            }
+        }
+        if !read_only {
+            for seq in list_blob_files(&dir)? {
+                if !blobs.refs.contains_key(&seq) {
+                    remove_blob_file(&dir, seq)?;
+                }
+            }
+        }
+
+        let mut stale_data_size = 0;
+        let gen_count = generations.len() + 1;
+        let mut removed = HashMap::new();
+        for (_, hints) in generations {
+            stale_data_size += load(&mut index, &mut removed, hints, &mut next_seq);
+        }
+
+        let live_data_size: u64 = index.values().map(CommandPosition::data_size).sum();
+        let blob_size: u64 = blobs.refs.values().map(|&(_, len)| len).sum();
+        stale_data_size += blob_size.saturating_sub(index.values().filter_map(|cmd_pos| cmd_pos.blob_len).sum());
+        let expiries = index.iter()
+            .filter_map(|(key, cmd_pos)| cmd_pos.expires_at.map(|expires_at| (expires_at, key.clone())))
+            .collect();
+        let reap_interval = options.ttl_reap_interval;
+        let cache = (options.cache_size > 0).then(|| Arc::new(ValueCache::new(options.cache_size)));
+        let index = Arc::new(RwLock::new(index));
+        let current_gen = gens.last().unwrap_or(&0)+1;
+        if !read_only {
+            // The last generation was the active one, compaction outputs always come before it.
+            if let (Some(archive), Some(&gen)) = (&options.archive_dir, gens.last()) {
+                archive_file(&log_path(&dir, gen), &log_path(archive, gen))?;
+            }
+            manifest.generations.insert(current_gen);
+            write_manifest(&dir, &manifest)?;
+        }
+        let generations = Arc::new(Generations {
+            dir: Arc::clone(&dir),
+            epoch: AtomicU64::new(0),
+            pins: Mutex::new(Pins::default()),
+            blobs: Mutex::new(blobs),
+            manifest: Mutex::new(manifest),
+        });
+        if read_only {
+            let readers = ReaderPool {
+                dir: Arc::clone(&dir),
+                active: None,
+                generations: Some(Arc::clone(&generations)),
+                idle: Mutex::new(vec![(0, readers)]),
+                cache,
+                _pin: None,
+            };
+            return Ok(KvStore {
+                index,
+                readers: Arc::new(readers),
+                writer: None,
+                log: None,
+                group_commit: None,
+                generations,
+                _reaper: None,
+                _lock: Arc::new(lock),
+            });
+        }
+
+        let writer = new_log_file(&dir, current_gen)?;
+        let group_commit = match options.durability {
+            Durability::GroupCommit { window } => Some(Arc::new(GroupCommit::new(window))),
+            _ => None,
+        };
+        let log = Arc::new(ActiveLog {
+            file: Mutex::new(ActiveFile {
+                writer,
+                end_seq: next_seq,
+            }),
+            flushed_seq: AtomicU64::new(next_seq),
+        });
+        let readers = ReaderPool {
+            dir: Arc::clone(&dir),
+            active: Some(Arc::clone(&log)),
+            generations: Some(Arc::clone(&generations)),
+            idle: Mutex::new(vec![(0, readers)]),
+            cache: cache.clone(),
+            _pin: None,
+        };
+        let writer = KvStoreWriter {
+            dir,
+            options,
+            log: Arc::clone(&log),
+            index: Arc::clone(&index),
+            stale_data_size,
+            live_data_size,
+            gen_count,
+            current_gen,
+            next_seq,
+            generations: Arc::clone(&generations),
+            cache,
+            compaction: None,
+            last_compaction: None,
+            active_hints: Vec::new(),
+            expiries,
+        };
+
+        let writer = Arc::new(Mutex::new(writer));
+        let reaper = {
+            let writer = Arc::clone(&writer);
+            Reaper::spawn(reap_interval, move || writer.lock().unwrap().reap_expired())
+        };
+
+        Ok(KvStore {
+            index,
+            readers: Arc::new(readers),
+            writer: Some(writer),
+            log: Some(log),
+            group_commit,
+            generations,
+            _reaper: Some(Arc::new(reaper)),
+            _lock: Arc::new(lock),
+        })
+    }
+}
+
+impl KvStore {
+    /// Compacts the log now and waits for it to finish. Reads and writes keep going meanwhile.
+    pub fn compact(&self) -> Result<()> {
+        let writer = self.writer.as_ref().ok_or(KvError::ReadOnly)?;
+        let handle = {
+            let mut writer = writer.lock().unwrap();
+            writer.finish_compaction()?;
+            writer.compact_log(true)?;
+            writer.compaction.as_mut().and_then(|compaction| compaction.handle.take())
+        };
+
+        // Another caller is already waiting for the running compaction.
+        let handle = match handle {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+
+        let result = join_compaction(&self.generations, handle);
+        let mut writer = writer.lock().unwrap();
+        if let Some(compaction) = writer.compaction.take() {
+            writer.complete_compaction(compaction.started, result.is_ok());
+        }
+        result
+    }
+
+    /// Rebuilds a data directory at `target` from `checkpoint`, then replays the writes kept in
+    /// `archive` (see `KvStoreOptions::archive_dir`) on top of it, up to `point`. Writes keep
+    /// their versions. `target` must not exist yet or be empty.
+    ///
+    /// The archive only gets a segment once it is sealed, so writes still in the active segment
+    /// of the archiving store are not replayed.
+    pub fn restore(checkpoint: &Path, archive: &Path, target: &Path, point: RestorePoint) -> Result<()> {
+        create_checkpoint_dir(target)?;
+        for entry in fs::read_dir(checkpoint)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && entry.file_name() != "LOCK" {
+                link_or_copy(&entry.path(), &target.join(entry.file_name()))?;
+            }
+        }
+
+        let store = KvStore::open(target)?;
+        let mut writer = store.writer.as_ref().ok_or(KvError::ReadOnly)?.lock().unwrap();
+        for gen in get_sorted_gens(archive)? {
+            writer.replay_archived(archive, gen, point)?;
+        }
+        writer.log.sync()?;
+        Ok(())
+    }
+
+    /// Checks a data directory without opening it. Every record of every generation is read,
+    /// along with the blobs they point at, and files the store does not expect are reported.
+    /// Fails with `KvError::Locked` while a store has the directory open for writing.
+    pub fn verify(dir: &Path) -> Result<VerifyReport> {
+        let _lock = lock_dir(dir, true)?;
+        let mut report = VerifyReport::default();
+        let manifest = read_manifest(dir).unwrap_or_else(|e| {
+            report.problems.push(Problem::CorruptManifest { message: e.to_string() });
+            None
+        });
+        let gens = get_sorted_gens(dir)?;
+        let listed: BTreeSet<u64> = match manifest {
+            Some(manifest) => manifest.generations,
+            None => gens.iter().copied().collect(),
+        };
+        // A generation is listed before its log is created, so the last one may not exist yet.
+        let last = gens.last().copied().unwrap_or_default();
+        for &gen in listed.iter().filter(|&&gen| gen < last && !gens.contains(&gen)) {
+            report.problems.push(Problem::MissingGeneration { gen });
+        }
+
+        let mut index = Index::new();
+        let mut removed = HashMap::new();
+        let mut next_seq = 0;
+        let mut blobs = HashMap::new();
+        let mut sizes = Vec::with_capacity(gens.len());
+        for &gen in &gens {
+            let path = log_path(dir, gen);
+            let mut hints = Vec::new();
+            match LogReader::open(&path).and_then(|mut reader| replay_into(gen, &mut reader, next_seq, &mut hints)) {
+                Ok(()) => {}
+                Err(e) if e.is_corruption() => {
+                    let offset = LogReader::open(&path).and_then(|mut reader| reader.valid_len()).unwrap_or(0);
+                    report.problems.push(match e {
+                        KvError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => Problem::TruncatedRecord { gen, offset },
+                        e => Problem::CorruptRecord { gen, offset, message: e.to_string() },
+                    });
+                    // Later damage is found by picking up again at each next valid record, as
+                    // repair does. Only a legacy JSON log stops at its first bad record.
+                    let file_len = fs::metadata(&path)?.len();
+                    for lost in salvage_log(&path)? {
+                        if let Salvaged::Lost { offset: from, len } = lost {
+                            if from <= offset {
+                                continue;
+                            }
+                            report.problems.push(if from + len == file_len {
+                                Problem::TruncatedRecord { gen, offset: from }
+                            } else {
+                                Problem::CorruptRecord { gen, offset: from, message: format!("{} unreadable bytes", len) }
+                            });
+                        }
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+
+            let records = hints.iter().filter(|hint| !matches!(hint, HintEntry::SeqMark { .. })).count() as u64;
+            if listed.contains(&gen) {
+                for hint in &hints {
+                    if let HintEntry::Set { seq, blob_len: Some(len), .. } = hint {
+                        blobs.insert(*seq, *len);
+                    }
+                }
+                load(&mut index, &mut removed, hints, &mut next_seq);
+            } else {
+                report.problems.push(Problem::UnlistedGeneration { gen });
+            }
+            sizes.push((gen, records, fs::metadata(&path)?.len()));
+        }
+
+        for (&seq, &len) in &blobs {
+            match read_blob_file(dir, seq) {
+                Ok(value) if value.len() as u64 == len => {}
+                Ok(_) | Err(KvError::ChecksumMismatch) => report.problems.push(Problem::CorruptBlob { seq }),
+                Err(KvError::Io(e)) if e.kind() == io::ErrorKind::NotFound => report.problems.push(Problem::MissingBlob { seq }),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        for cmd_pos in index.values() {
+            *live.entry(cmd_pos.gen).or_default() += cmd_pos.size;
+        }
+        for (gen, records, len) in sizes {
+            let live_bytes = live.get(&gen).copied().unwrap_or_default();
+            report.generations.push(GenerationReport {
+                gen,
+                records,
+                live_bytes,
+                stale_bytes: len.saturating_sub(LOG_HEADER_SIZE).saturating_sub(live_bytes),
+            });
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            names.push((entry.file_name().to_string_lossy().into_owned(), entry.file_type()?.is_dir()));
+        }
+        names.sort();
+        for (name, is_dir) in names {
+            report.problems.extend(check_file(name, is_dir, &gens, &blobs));
+        }
+        Ok(report)
+    }
+
+    /// Salvages what can still be read from damaged generations, the ones `verify` reports
+    /// corrupt or truncated records in. Reading skips over unreadable regions to the next valid
+    /// record. The salvaged records that are still live go to a new generation, and the damaged
+    /// logs move to the `quarantine` subdirectory. Generations that read cleanly are left alone.
+    /// Fails with `KvError::Locked` while a store has the directory open.
+    pub fn repair(dir: &Path) -> Result<RepairReport> {
+        let _lock = lock_dir(dir, false)?;
+        let mut manifest = match read_manifest(dir) {
+            Ok(Some(manifest)) => recover_manifest(dir, manifest, false)?,
+            // Without a readable manifest every log counts.
+            _ => Manifest {
+                generations: get_sorted_gens(dir)?.into_iter().collect(),
+                compaction: None,
+            },
+        };
+
+        let mut report = RepairReport::default();
+        let mut index = Index::new();
+        let mut removed = HashMap::new();
+        let mut next_seq = 0;
+        let mut salvaged = Vec::new();
+        for &gen in &manifest.generations {
+            let path = log_path(dir, gen);
+            if !path.exists() {
+                continue;
+            }
+            let mut hints = Vec::new();
+            match LogReader::open(&path).and_then(|mut reader| replay_into(gen, &mut reader, next_seq, &mut hints)) {
+                Ok(()) => {
+                    load(&mut index, &mut removed, hints, &mut next_seq);
+                    continue;
+                }
+                Err(e) if e.is_corruption() => {}
+                Err(e) => return Err(e),
+            }
+
+            let mut entries = Vec::new();
+            let mut batches = Batches::default();
+            let mut prev_key = None;
+            let mut lost = Vec::new();
+            for item in salvage_log(&path)? {
+                match item {
+                    Salvaged::Entry(entry) => {
+                        let seq = entry.seq.unwrap_or(next_seq);
+                        next_seq = next_seq.max(seq + 1);
+                        if let Some(key) = entry.command.key() {
+                            for range in lost.drain(..) {
+                                report.lost.push(LostRange { next_key: Some(key.to_vec()), ..range });
+                            }
+                            prev_key = Some(key.to_vec());
+                        }
+                        batches.push(seq, entry, &mut |seq, entry| {
+                            entries.push((seq, entry));
+                            Ok(())
+                        })?;
+                    }
+                    Salvaged::Lost { offset, len } => {
+                        batches.interrupt();
+                        lost.push(LostRange { gen, offset, len, prev_key: prev_key.clone(), next_key: None });
+                    }
+                }
+            }
+            report.lost.extend(lost);
+
+            let hints = entries.iter()
+                .filter_map(|(seq, entry)| hint_of(gen, *seq, LogEntry {
+                    command: entry.command.clone(),
+                    ..*entry
+                }))
+                .collect();
+            load(&mut index, &mut removed, hints, &mut next_seq);
+            report.quarantined.push(gen);
+            salvaged.push((gen, entries));
+        }
+        if salvaged.is_empty() {
+            return Ok(report);
+        }
+
+        // Like a merge, the output keeps the records still live and the tombstones still hiding
+        // something, along with the highest sequence number seen so versions keep growing.
+        let last = get_sorted_gens(dir)?.last().copied().unwrap_or_default();
+        let output = last.max(manifest.generations.last().copied().unwrap_or_default()) + 1;
+        let mut writer = new_log_file(dir, output)?;
+        let mut hints = Vec::new();
+        let mut max_seq = 0;
+        for (gen, entries) in salvaged {
+            for (seq, entry) in entries {
+                max_seq = max_seq.max(seq);
+                let live = match &entry.command {
+                    Command::Remove { key } => !index.contains_key(key),
+                    command => command.key()
+                        .and_then(|key| index.get(key))
+                        .is_some_and(|cmd_pos| cmd_pos.gen == gen && cmd_pos.log_start_pos == entry.pos),
+                };
+                if !live {
+                    continue;
+                }
+
+                let command = match entry.command {
+                    Command::SetBlob { key, len, .. }
+                        if !read_blob_file(dir, seq).is_ok_and(|value| value.len() as u64 == len) => {
+                        report.lost_blobs.push(key.clone());
+                        Command::Remove { key }
+                    }
+                    command => command,
+                };
+                let pos = writer.pos;
+                let size = write_record(&mut writer, seq, entry.timestamp, &command)?;
+                hints.extend(hint_of(output, seq, LogEntry { pos, size, seq: Some(seq), timestamp: entry.timestamp, command }));
+                report.salvaged_records += 1;
+            }
+        }
+        write_record(&mut writer, max_seq, Some(now_millis()), &Command::SeqMark)?;
+        hints.push(HintEntry::SeqMark { seq: max_seq });
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        write_hint_file(dir, output, writer.pos, &hints)?;
+        drop(writer);
+        seal_log_file(dir, output)?;
+
+        // The originals go to quarantine before the manifest stops listing them, since opening
+        // the store deletes the logs it does not list.
+        let quarantine = dir.join(QUARANTINE_DIR);
+        fs::create_dir_all(&quarantine)?;
+        for &gen in &report.quarantined {
+            archive_file(&log_path(dir, gen), &log_path(&quarantine, gen))?;
+            manifest.generations.remove(&gen);
+        }
+        manifest.generations.insert(output);
+        write_manifest(dir, &manifest)?;
+        for &gen in &report.quarantined {
+            remove_generation(dir, gen)?;
+        }
+        report.output = Some(output);
+        Ok(report)
+    }
+
+    /// Returns the counters of the value cache, all zero when `KvStoreOptions::cache_size` is 0.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.readers.cache.as_ref().map(|cache| cache.stats()).unwrap_or_default()
+    }
+
+    /// Returns a read-only view of the store as it is now. Later writes do not show through it.
+    pub fn snapshot(&self) -> KvSnapshot {
+        // Pinning under the index lock keeps a running compaction from deleting any generation
+        // the copied index points into.
+        let index = self.index.read().unwrap();
+        let gens = index.values().map(|cmd_pos| cmd_pos.gen).collect();
+        let pin = Generations::pin(&self.generations, gens);
+        let readers = ReaderPool {
+            dir: Arc::clone(&self.generations.dir),
+            active: self.log.clone(),
+            generations: None,
+            idle: Mutex::new(Vec::new()),
+            cache: None,
+            _pin: Some(pin),
+        };
+        KvSnapshot::new(index.clone(), readers)
+    }
+
+    /// Returns the value of `key` with its version, the sequence number of the write that
+    /// stored it. Versions only grow, so a changed version means the value was written again.
+    pub fn get_with_version(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        let index = self.index.read().unwrap();
+        match index.get(key) {
+            Some(cmd_pos) if !is_expired(cmd_pos.expires_at, now_millis()) => {
+                Ok(Some((self.readers.read_cached(key, cmd_pos)?, cmd_pos.seq)))
+            }
+            _ => Ok(None)
+        }
+    }
+
+    /// Sets `key` if its version is still `expected_version`, `None` meaning the key must not
+    /// exist, and returns the new version. Fails with `KvError::ConditionFailed` otherwise.
+    pub fn set_if_version(&self, key: Vec<u8>, value: Vec<u8>, expected_version: Option<u64>) -> Result<u64> {
+        self.write(|writer| {
+            if self.version(&key) != expected_version {
+                return Err(KvError::ConditionFailed);
+            }
+
+            writer.set(key.clone(), value, None)?;
+            Ok(self.version(&key).unwrap_or_default())
+        })
+    }
+
+    /// Removes `key` if its version is still `expected_version`.
+    pub fn remove_if_version(&self, key: &[u8], expected_version: u64) -> Result<()> {
+        self.write(|writer| {
+            if self.version(key) != Some(expected_version) {
+                return Err(KvError::ConditionFailed);
+            }
+
+            writer.remove(key)
+        })
+    }
+
+    fn version(&self, key: &[u8]) -> Option<u64> {
+        self.index.read().unwrap()
+            .get(key)
+            .filter(|cmd_pos| !is_expired(cmd_pos.expires_at, now_millis()))
+            .map(|cmd_pos| cmd_pos.seq)
+    }
+
+    // Every write goes through the writer lock, so holding it keeps the value from changing
+    // between the check and the write.
+    fn write_if(&self, key: Vec<u8>, new: Option<Vec<u8>>, condition: impl FnOnce(Option<&[u8]>) -> bool) -> Result<()> {
+        self.write(|writer| {
+            let current = self.get_bytes(&key)?;
+            if !condition(current.as_deref()) {
+                return Err(KvError::ConditionFailed);
+            }
+
+            match new {
+                Some(value) => writer.set(key, value, None),
+                None if current.is_some() => writer.remove(&key),
+                None => Ok(()),
+            }
+        })
+    }
+
+    // Runs a write under the writer lock. With group commit, the sync happens after the lock
+    // is released, so writers arriving meanwhile can share it.
+    fn write<T>(&self, op: impl FnOnce(&mut KvStoreWriter) -> Result<T>) -> Result<T> {
+        let mut writer = self.writer.as_ref().ok_or(KvError::ReadOnly)?.lock().unwrap();
+        let result = op(&mut writer)?;
+        let ticket = writer.next_seq;
+        drop(writer);
+
+        if let (Some(group_commit), Some(log)) = (&self.group_commit, &self.log) {
+            group_commit.commit(ticket, || log.sync())?;
+        }
+        Ok(result)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(|writer| writer.set(key, value, None))
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        read_key(&self.index, &self.readers, key)
+    }
+
+    fn scan(&self, range: KeyRange, options: ScanOptions) -> Result<ScanIter> {
+        Ok(Box::new(KvStoreScan::new(&self.index, &self.readers, range, options)))
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.write(|writer| writer.remove(key))
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(|writer| writer.set(key, value, Some(deadline(ttl))))
+    }
+
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        self.write_if(key, new, |current| current == expected.as_deref())
+    }
+
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_if(key, Some(value), |current| current.is_none())
+    }
+
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_if(key, Some(value), |current| current.is_some())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(|writer| writer.write_batch(batch))
+    }
+
+    // Read from a snapshot, so a dump sees the store as of one moment.
+    fn entries(&self) -> Result<DumpIter> {
+        Ok(self.snapshot().entries())
+    }
+
+    // A read-only store keeps no counters, so it counts what is on disk instead. The writer
+    // lock is let go before the index is read, as anywhere else.
+    fn stats(&self) -> Result<EngineStats> {
+        let counters = self.writer.as_ref().map(|writer| {
+            let writer = writer.lock().unwrap();
+            (writer.live_data_size, writer.stale_data_size, writer.gen_count as u64, writer.last_compaction)
+        });
+        let now = now_millis();
+        let index = self.index.read().unwrap();
+        let (live_bytes, stale_bytes, generations, last_compaction) = match counters {
+            Some(counters) => counters,
+            None => {
+                let live_bytes: u64 = index.values().map(CommandPosition::data_size).sum();
+                let gens = self.generations.live_generations();
+                let total = gens.iter()
+                    .map(|&gen| fs::metadata(log_path(&self.generations.dir, gen)).map_or(0, |metadata| metadata.len()))
+                    .map(|len| len.saturating_sub(LOG_HEADER_SIZE))
+                    .sum::<u64>() + self.generations.blob_size();
+                (live_bytes, total.saturating_sub(live_bytes), gens.len() as u64, None)
+            }
+        };
+        Ok(EngineStats {
+            live_keys: index.values().filter(|cmd_pos| !is_expired(cmd_pos.expires_at, now)).count() as u64,
+            live_bytes,
+            stale_bytes,
+            generations,
+            last_compaction,
+            cache: self.cache_stats(),
+        })
+    }
+
+    // Sealed generations and blobs never change, so they are hard linked where the file system
+    // allows it. The active generation is copied up to the end it had when the checkpoint
+    // started, and the pin keeps compaction from deleting anything before it is copied.
+    fn checkpoint(&self, target: &Path) -> Result<()> {
+        create_checkpoint_dir(target)?;
+        let (pin, active) = {
+            let writer = self.writer.as_ref().map(|writer| writer.lock().unwrap());
+            let active = match &writer {
+                Some(writer) => {
+                    writer.log.flush()?;
+                    Some((writer.current_gen, writer.log.file.lock().unwrap().writer.pos))
+                }
+                None => None,
+            };
+            (Generations::pin_live(&self.generations), active)
+        };
+
+        let dir = self.generations.dir.as_path();
+        for &gen in &pin.gens {
+            match active {
+                Some((active_gen, len)) if active_gen == gen => {
+                    let mut source = File::open(generation_path(dir, gen))?.take(len);
+                    let mut copy = File::create(log_path(target, gen))?;
+                    io::copy(&mut source, &mut copy)?;
+                    copy.sync_all()?;
+                }
+                _ => {
+                    link_or_copy(&generation_path(dir, gen), &log_path(target, gen))?;
+                    // A retired generation lost its hint file, open replays it instead.
+                    let hint = hint_path(dir, gen);
+                    if hint.exists() {
+                        link_or_copy(&hint, &hint_path(target, gen))?;
+                    }
+                }
+            }
+        }
+
+        // Blobs of records past the copied end of the active generation are deleted on open.
+        for seq in self.generations.blobs_of(&pin.gens) {
+            link_or_copy(&blob_path(dir, seq), &blob_path(target, seq))?;
+        }
+
+        write_manifest(target, &Manifest {
+            generations: pin.gens.iter().copied().collect(),
+            compaction: None,
+        })
+    }
+}
+
+pub(crate) fn read_key(index: &RwLock<Index>, readers: &ReaderPool, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    // Holding the index lock keeps compaction from deleting the generation being read.
+    let index = index.read().unwrap();
+    match index.get(key) {
+        Some(cmd_pos) if !is_expired(cmd_pos.expires_at, now_millis()) => {
+            Ok(Some(readers.read_cached(key, cmd_pos)?))
+        }
+        _ => Ok(None)
+    }
+}
+
+// Reads the range in batches, so the index lock is never held for a whole scan. Each batch
+// moves the start (or the end, in reverse) of the range past the keys it returned.
+pub(crate) struct KvStoreScan {
+    index: Arc<RwLock<Index>>,
+    readers: Arc<ReaderPool>,
+    range: KeyRange,
+    reverse: bool,
+    remaining: usize,
+    buffer: VecDeque<Result<DumpEntry>>,
+}
+
+// The same scan, with the expiry deadline of every pair.
+pub(crate) struct KvStoreEntries(pub(crate) KvStoreScan);
+
+impl KvStoreScan {
+    pub(crate) fn new(index: &Arc<RwLock<Index>>, readers: &Arc<ReaderPool>, range: KeyRange, options: ScanOptions) -> KvStoreScan {
+        KvStoreScan {
+            index: Arc::clone(index),
+            readers: Arc::clone(readers),
+            range,
+            reverse: options.reverse,
+            remaining: options.limit.unwrap_or(usize::MAX),
+            buffer: VecDeque::new(),
+        }
+    }
+
+    fn fill_buffer(&mut self) {
+        let batch_size = self.remaining.min(SCAN_BATCH_SIZE);
+        if is_empty_range(&self.range) {
+            self.remaining = 0;
+            return;
+        }
+
+        let now = now_millis();
+        let index = self.index.read().unwrap();
+        let range = index.range::<Vec<u8>, _>(self.range.clone())
+            .filter(|(_, cmd_pos)| !is_expired(cmd_pos.expires_at, now));
+        let batch: Vec<_> = if self.reverse {
+            range.rev().take(batch_size).collect()
+        } else {
+            range.take(batch_size).collect()
+        };
+
+        if batch.len() < batch_size {
+            self.remaining = 0;
+        } else {
+            self.remaining -= batch.len();
+        }
+
+        if let Some((last_key, _)) = batch.last() {
+            let bound = Bound::Excluded(last_key.to_vec());
+            if self.reverse {
+                self.range.1 = bound;
+            } else {
+                self.range.0 = bound;
+            }
+        }
+
+        for (key, cmd_pos) in batch {
+            let value = self.readers.read_value(cmd_pos);
+            let failed = value.is_err();
+            self.buffer.push_back(value.map(|value| DumpEntry {
+                key: key.clone(),
+                value,
+                expires_at: cmd_pos.expires_at,
+            }));
+            if failed {
+                self.remaining = 0;
+                break;
+            }
+        }
+    }
+}
+
+impl KvStoreScan {
+    fn next_entry(&mut self) -> Option<Result<DumpEntry>> {
+        if self.buffer.is_empty() && self.remaining > 0 {
+            self.fill_buffer();
+        }
+        self.buffer.pop_front()
+    }
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|entry| entry.map(|entry| (entry.key, entry.value)))
+    }
+}
+
+impl Iterator for KvStoreEntries {
+    type Item = Result<DumpEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_entry()
+    }
+}
+
+impl Generations {
+    fn pin(generations: &Arc<Generations>, gens: BTreeSet<u64>) -> GenerationPin {
+        let mut pins = generations.pins.lock().unwrap();
+        for &gen in &gens {
+            *pins.refs.entry(gen).or_default() += 1;
+        }
+
+        GenerationPin {
+            generations: Arc::clone(generations),
+            gens,
+        }
+    }
+
+    // Deletes the generations compaction replaced, unless a snapshot still reads from them.
+    fn retire(&self, gens: &[u64]) -> Result<()> {
+        let mut pins = self.pins.lock().unwrap();
+        for &gen in gens {
+            if !pins.refs.contains_key(&gen) {
+                remove_generation(&self.dir, gen)?;
+                self.release_blobs(gen)?;
+            } else if pins.retired.insert(gen) {
+                fs::rename(log_path(&self.dir, gen), retired_path(&self.dir, gen))?;
+                remove_hint_file(&self.dir, gen)?;
+            }
+        }
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn unpin(&self, gens: &BTreeSet<u64>) -> Result<()> {
+        let mut pins = self.pins.lock().unwrap();
+        for gen in gens {
+            if let Entry::Occupied(mut entry) = pins.refs.entry(*gen) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                    if pins.retired.remove(gen) {
+                        fs::remove_file(retired_path(&self.dir, *gen))?;
+                        self.release_blobs(*gen)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Generations {
+    fn live_generations(&self) -> Vec<u64> {
+        self.manifest.lock().unwrap().generations.iter().copied().collect()
+    }
+
+    // Pins every live generation, so none of them is deleted while a checkpoint copies it.
+    fn pin_live(generations: &Arc<Generations>) -> GenerationPin {
+        let manifest = generations.manifest.lock().unwrap();
+        Generations::pin(generations, manifest.generations.iter().copied().collect())
+    }
+
+    fn add_generation(&self, gen: u64) -> Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.generations.insert(gen);
+        write_manifest(&self.dir, &manifest)
+    }
+
+    // Recorded before the output log is created, so a crash can always be cleaned up after.
+    fn begin_compaction(&self, inputs: Vec<u64>, output: u64) -> Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.compaction = Some(CompactionRecord { inputs, output });
+        write_manifest(&self.dir, &manifest)
+    }
+
+    // Replaces the inputs of the running compaction with its output, unless it came out
+    // empty, and returns the inputs.
+    fn commit_compaction(&self, keep_output: bool) -> Result<Vec<u64>> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let Some(compaction) = manifest.compaction.take() else {
+            return Ok(Vec::new());
+        };
+        for gen in &compaction.inputs {
+            manifest.generations.remove(gen);
+        }
+        if keep_output {
+            manifest.generations.insert(compaction.output);
+        }
+        write_manifest(&self.dir, &manifest)?;
+        Ok(compaction.inputs)
+    }
+
+    // Drops the output of a compaction that failed before it was committed.
+    fn abort_compaction(&self) -> Result<()> {
+        let output = {
+            let mut manifest = self.manifest.lock().unwrap();
+            match manifest.compaction.take() {
+                Some(compaction) => {
+                    write_manifest(&self.dir, &manifest)?;
+                    remove_generation(&self.dir, compaction.output)?;
+                    compaction.output
+                }
+                None => return Ok(()),
+            }
+        };
+        // The inputs still refer to every blob the output copied a record of, so none of them
+        // goes away here.
+        self.release_blobs(output)
+    }
+
+    fn add_blob(&self, gen: u64, seq: u64, len: u64) {
+        self.blobs.lock().unwrap().add(gen, seq, len);
+    }
+
+    fn blobs_of(&self, gens: &BTreeSet<u64>) -> BTreeSet<u64> {
+        let blobs = self.blobs.lock().unwrap();
+        gens.iter()
+            .filter_map(|gen| blobs.gens.get(gen))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    fn blob_size(&self) -> u64 {
+        self.blobs.lock().unwrap().refs.values().map(|&(_, len)| len).sum()
+    }
+
+    // Deletes the blobs only the deleted generation `gen` referred to.
+    fn release_blobs(&self, gen: u64) -> Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        for seq in blobs.gens.remove(&gen).unwrap_or_default() {
+            if let Entry::Occupied(mut entry) = blobs.refs.entry(seq) {
+                entry.get_mut().0 -= 1;
+                if entry.get().0 == 0 {
+                    entry.remove();
+                    remove_blob_file(&self.dir, seq)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-impl KvStore {
-    pub fn open(dir: impl Into<PathBuf>) -> Result<KvStore> {
-        let dir = dir.into();
-        fs::create_dir_all(&dir)?;
+impl BlobRefs {
+    fn add(&mut self, gen: u64, seq: u64, len: u64) {
+        self.gens.entry(gen).or_default().push(seq);
+        self.refs.entry(seq).or_insert((0, len)).0 += 1;
+    }
+}
 
-        let mut index: BTreeMap<String, CommandPosition> = BTreeMap::new();
-        let mut readers: HashMap<u64, BufReaderWithPos<File>> = HashMap::new();
-        let gens = get_sorted_gens(&dir)?;
+impl Drop for GenerationPin {
+    fn drop(&mut self) {
+        let _ = self.generations.unpin(&self.gens);
+    }
+}
 
-        let mut stale_data_size = 0;
-        for &gen in &gens {
-            let file_path = log_path(&dir, gen);
-            let read_file = OpenOptions::new()
-                .read(true)
-                .open(&file_path)?;
-            let mut reader = BufReaderWithPos::new(read_file)?;
-            stale_data_size += load(gen, &mut index, &mut reader)?;
-            readers.insert(gen, reader);
+impl ActiveLog {
+    fn flush(&self) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.writer.flush()?;
+        self.flushed_seq.store(file.end_seq, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Syncs everything written so far and returns the sequence number it is durable up to.
+    // The sync itself runs on a second handle, so writers can keep appending meanwhile.
+    fn sync(&self) -> Result<u64> {
+        let (synced_seq, file) = {
+            let mut file = self.file.lock().unwrap();
+            file.writer.flush()?;
+            self.flushed_seq.store(file.end_seq, Ordering::SeqCst);
+            (file.end_seq, file.writer.get_ref().try_clone()?)
+        };
+        file.sync_data()?;
+        Ok(synced_seq)
+    }
+
+    fn ensure_flushed(&self, seq: u64) -> Result<()> {
+        if seq >= self.flushed_seq.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl ReaderPool {
+    // Point reads go through the value cache, when the pool has one. Scans bypass it, so they
+    // do not push the hot values out.
+    fn read_cached(&self, key: &[u8], cmd_pos: &CommandPosition) -> Result<Vec<u8>> {
+        let Some(cache) = &self.cache else {
+            return self.read_value(cmd_pos);
+        };
+
+        if let Some(value) = cache.get(key, cmd_pos.seq) {
+            return Ok(value);
         }
+        let value = self.read_value(cmd_pos)?;
+        cache.insert(key.to_vec(), cmd_pos.seq, value.clone());
+        Ok(value)
+    }
 
-        let current_gen = gens.last().unwrap_or(&0)+1;
+    fn read_value(&self, cmd_pos: &CommandPosition) -> Result<Vec<u8>> {
+        if cmd_pos.blob_len.is_some() {
+            return read_blob_file(&self.dir, cmd_pos.seq);
+        }
 
-        let writer = new_log_file(&dir, current_gen, &mut readers)?;
-        Ok(KvStore {
-            dir,
-            writer,
-            readers,
-            index,
-            stale_data_size,
-            current_gen,
-        })
+        match self.read_command(cmd_pos)? {
+            Command::Set { value, .. } | Command::SetWithTtl { value, .. } => {
+                Ok(value)
+            }
+            _ => {
+                Err(KvError::UnexpectedCommandType)
+            }
+        }
+    }
+
+    fn read_command(&self, cmd_pos: &CommandPosition) -> Result<Command> {
+        if let Some(active) = &self.active {
+            active.ensure_flushed(cmd_pos.seq)?;
+        }
+        let (mut epoch, mut readers) = self.idle.lock().unwrap().pop().unwrap_or_default();
+
+        if let Some(generations) = &self.generations {
+            let current = generations.epoch.load(Ordering::SeqCst);
+            if epoch != current {
+                let manifest = generations.manifest.lock().unwrap();
+                readers.retain(|gen, _| manifest.generations.contains(gen));
+                epoch = current;
+            }
+        }
+        let cmd = match readers.entry(cmd_pos.gen) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => open_generation(&self.dir, cmd_pos.gen)
+                .map(|reader| entry.insert(reader)),
+        }.and_then(|reader| reader.read_command(cmd_pos.log_start_pos, cmd_pos.size));
+
+        self.idle.lock().unwrap().push((epoch, readers));
+        cmd
+    }
+}
+
+impl KvStoreWriter {
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.reap_compaction()?;
+
+        let cmd = match expires_at {
+            Some(expires_at) => Command::set_with_ttl(key, value, expires_at),
+            None => Command::set(key, value),
+        };
+        let cmd_pos = self.append(&cmd)?;
+        let index = Arc::clone(&self.index);
+        self.index_command(&mut index.write().unwrap(), cmd, cmd_pos);
+        self.maintain()
     }
 
-    fn compact_log(&mut self) -> Result<()> {
-        let compaction_gen = self.current_gen+1;
-        self.current_gen += 2;
+    // Expired keys count as removed already, the reaper writes their tombstones.
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.reap_compaction()?;
+
+        let exists = self.index.read().unwrap()
+            .get(key)
+            .is_some_and(|cmd_pos| !is_expired(cmd_pos.expires_at, now_millis()));
+        if exists {
+            let cmd = Command::remove(key.to_vec());
+            let cmd_pos = self.append(&cmd)?;
+            let index = Arc::clone(&self.index);
+            self.index_command(&mut index.write().unwrap(), cmd, cmd_pos);
+            self.maintain()
+        } else {
+            Err(KvError::KeyNotFound)
+        }
+    }
 
-        self.writer = new_log_file(&self.dir, self.current_gen, &mut self.readers)?;
-        let mut compaction_writer = new_log_file(&self.dir, compaction_gen, &mut self.readers)?;
+    // The batch is flushed once its commit marker is written, and the index only changes after
+    // that, so readers see either none or all of it.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.reap_compaction()?;
 
-        let mut write_pos = 0;
-        for cmd_pos in self.index.values_mut() {
-            let reader = self.readers.get_mut(&cmd_pos.gen)
-                .ok_or(KvError::KeyNotFound)?;
-            if reader.pos != cmd_pos.log_start_pos {
-                reader.seek(SeekFrom::Start(cmd_pos.log_start_pos))?;
+        // Removes of keys that do not exist by then are dropped, like single removes they
+        // would have nothing to delete.
+        let mut present: HashMap<Vec<u8>, bool> = HashMap::new();
+        let mut commands = Vec::with_capacity(batch.len());
+        {
+            let index = self.index.read().unwrap();
+            for cmd in batch.commands {
+                match &cmd {
+                    Command::Set { key, .. } => {
+                        present.insert(key.clone(), true);
+                    }
+                    Command::Remove { key } => {
+                        let exists = present.get(key).copied()
+                            .unwrap_or_else(|| index.contains_key(key));
+                        if !exists {
+                            continue;
+                        }
+                        present.insert(key.clone(), false);
+                    }
+                    _ => return Err(KvError::UnexpectedCommandType),
+                }
+                commands.push(cmd);
             }
+        }
+        if commands.is_empty() {
+            return Ok(());
+        }
 
-            let mut cmd_reader = reader.take(cmd_pos.size);
-            let len = io::copy(&mut cmd_reader, &mut compaction_writer)?;
-            *cmd_pos = CommandPosition{
-                log_start_pos: write_pos,
-                size: len,
-                gen: compaction_gen
-            };
-            write_pos += len;
+        self.append_record(&Command::BatchBegin { count: commands.len() as u64 })?;
+        let mut positions = Vec::with_capacity(commands.len());
+        for cmd in &commands {
+            positions.push(self.append_record(cmd)?);
+        }
+        self.append_record(&Command::BatchCommit)?;
+        self.write_through()?;
+
+        let index = Arc::clone(&self.index);
+        let mut index = index.write().unwrap();
+        for (cmd, cmd_pos) in commands.into_iter().zip(positions) {
+            self.index_command(&mut index, cmd, cmd_pos);
+        }
+        drop(index);
+        self.maintain()
+    }
+
+    // Points the index at a command that was just appended to the active generation.
+    fn index_command(&mut self, index: &mut Index, cmd: Command, cmd_pos: CommandPosition) {
+        let (key, old_cmd) = match cmd {
+            Command::Set { key, .. } | Command::SetWithTtl { key, .. } | Command::SetBlob { key, .. } => {
+                self.active_hints.push(HintEntry::Set {
+                    key: key.clone(),
+                    gen: cmd_pos.gen,
+                    pos: cmd_pos.log_start_pos,
+                    size: cmd_pos.size,
+                    seq: cmd_pos.seq,
+                    expires_at: cmd_pos.expires_at,
+                    blob_len: cmd_pos.blob_len,
+                });
+                if let Some(expires_at) = cmd_pos.expires_at {
+                    self.expiries.insert((expires_at, key.clone()));
+                }
+                self.live_data_size += cmd_pos.data_size();
+                let old_cmd = index.insert(key.clone(), cmd_pos);
+                (key, old_cmd)
+            }
+            Command::Remove { key } => {
+                self.stale_data_size += cmd_pos.size;
+                self.active_hints.push(HintEntry::Remove {
+                    key: key.clone(),
+                    size: cmd_pos.size,
+                    seq: cmd_pos.seq,
+                });
+                let old_cmd = index.remove(&key);
+                (key, old_cmd)
+            }
+            _ => return,
+        };
+
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        if let Some(old_cmd) = old_cmd {
+            self.stale_data_size += old_cmd.data_size();
+            self.live_data_size = self.live_data_size.saturating_sub(old_cmd.data_size());
+            if let Some(expires_at) = old_cmd.expires_at {
+                if old_cmd.expires_at != cmd_pos.expires_at {
+                    self.expiries.remove(&(expires_at, key));
+                }
+            }
+        }
+    }
+
+    // Writes tombstones for expired keys, so compaction can drop their values.
+    fn reap_expired(&mut self) -> Result<()> {
+        let now = now_millis();
+        while let Some((expires_at, key)) = self.expiries.first().cloned() {
+            if expires_at > now {
+                break;
+            }
+
+            self.expiries.pop_first();
+            let current = self.index.read().unwrap().get(&key).and_then(|cmd_pos| cmd_pos.expires_at);
+            if current == Some(expires_at) {
+                self.reap_compaction()?;
+                let cmd = Command::remove(key);
+                let cmd_pos = self.append(&cmd)?;
+                let index = Arc::clone(&self.index);
+                self.index_command(&mut index.write().unwrap(), cmd, cmd_pos);
+            }
+        }
+
+        self.maintain()
+    }
+
+    // Seals the active segment once it is full, and starts compaction once enough of the log
+    // is stale.
+    fn maintain(&mut self) -> Result<()> {
+        if self.log.file.lock().unwrap().writer.pos >= self.options.max_segment_size {
+            self.rotate(self.current_gen + 1)?;
+        }
+        if self.should_compact() {
+            self.compact_log(false)?;
         }
+        Ok(())
+    }
+
+    fn should_compact(&self) -> bool {
+        self.options.auto_compaction
+            && self.gen_count >= self.options.min_generations
+            && self.stale_data_size >= self.options.compaction_threshold
+            && self.stale_data_size as f64 >= self.options.compaction_ratio * self.live_data_size as f64
+    }
+
+    // Compaction runs on a background thread and writes a new generation, so reads and writes
+    // keep going against the new active generation meanwhile. Unless `full` is set, only the
+    // segments that are mostly stale get merged, when there are any. The active segment is
+    // sealed first either way.
+    fn compact_log(&mut self, full: bool) -> Result<()> {
+        if self.compaction.is_some() {
+            return Ok(());
+        }
+
+        let segments = if full { Vec::new() } else { self.merge_candidates()? };
+        if !segments.is_empty() {
+            let merge_gen = self.current_gen + 1;
+            self.rotate(self.current_gen + 2)?;
+            self.generations.begin_compaction(segments.clone(), merge_gen)?;
+            let merge_writer = new_log_file(&self.dir, merge_gen)?;
+
+            let generations = Arc::clone(&self.generations);
+            let index = Arc::clone(&self.index);
+            let handle = thread::spawn(move || {
+                merge(&generations, &index, segments, merge_gen, merge_writer)
+                    .inspect_err(|_| { let _ = generations.abort_compaction(); })
+            });
 
-        compaction_writer.flush()?;
+            self.gen_count += 1;
+            self.compaction = Some(Compaction { handle: Some(handle), started: Instant::now() });
+            return Ok(());
+        }
+
+        // A full compaction copies the active generation as well, so it needs no hint file.
+        let compaction_gen = self.current_gen + 1;
+        self.roll_over(self.current_gen + 2)?;
+        self.active_hints.clear();
+        let inputs = self.generations.live_generations()
+            .into_iter()
+            .filter(|&gen| gen < compaction_gen)
+            .collect();
+        self.generations.begin_compaction(inputs, compaction_gen)?;
+        let compaction_writer = new_log_file(&self.dir, compaction_gen)?;
 
-        let stale_gens: Vec<_> = self.readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
+        let snapshot: Vec<_> = self.index.read().unwrap()
+            .iter()
+            .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
             .collect();
+        let generations = Arc::clone(&self.generations);
+        let index = Arc::clone(&self.index);
+        let last_seq = self.next_seq.checked_sub(1);
+        let handle = thread::spawn(move || {
+            compact(&generations, &index, snapshot, last_seq, compaction_gen, compaction_writer)
+                .inspect_err(|_| { let _ = generations.abort_compaction(); })
+        });
+
+        self.gen_count += 2;
+        self.compaction = Some(Compaction { handle: Some(handle), started: Instant::now() });
+        Ok(())
+    }
+
+    // Segments where at least half of the bytes can be reclaimed, the active one included.
+    // Empty segments are merged away as well.
+    // Merging keeps the tombstones of keys that are still removed, so those do not count.
+    fn merge_candidates(&self) -> Result<Vec<u64>> {
+        let index = self.index.read().unwrap();
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        for cmd_pos in index.values() {
+            *live.entry(cmd_pos.gen).or_default() += cmd_pos.size;
+        }
 
-        for gen in stale_gens {
-            self.readers.remove(&gen);
-            fs::remove_file(log_path(&self.dir, gen))?;
+        let mut candidates = Vec::new();
+        for gen in self.generations.live_generations() {
+            let (len, tombstones) = if gen == self.current_gen {
+                (self.log.file.lock().unwrap().writer.pos, tombstone_size(&index, &self.active_hints))
+            } else {
+                let len = fs::metadata(log_path(&self.dir, gen))?.len();
+                match read_hint_file(&self.dir, gen, len)? {
+                    Some(hints) => (len, tombstone_size(&index, &hints)),
+                    // Merging reads a segment through its hints.
+                    None => continue,
+                }
+            };
+
+            let len = len.saturating_sub(LOG_HEADER_SIZE);
+            let kept = live.get(&gen).copied().unwrap_or_default() + tombstones;
+            let reclaimable = len.saturating_sub(kept);
+            if reclaimable * 2 >= len {
+                candidates.push(gen);
+            }
         }
+        Ok(candidates)
+    }
 
-        self.stale_data_size = 0;
+    // Seals the active segment with its hint file and starts `new_gen`.
+    fn rotate(&mut self, new_gen: u64) -> Result<()> {
+        let sealed_gen = self.current_gen;
+        let len = self.roll_over(new_gen)?;
+        write_hint_file(&self.dir, sealed_gen, len, &self.active_hints)?;
+        self.active_hints.clear();
+        seal_log_file(&self.dir, sealed_gen)?;
+        self.gen_count += 1;
         Ok(())
     }
-}
 
-impl KvsEngine for KvStore {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key.clone(), value);
-        let log_start_pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-
-        let log_end_pos = self.writer.pos;
-        let size = log_end_pos - log_start_pos;
-        let val = self.index.insert(key.clone(), CommandPosition {
+    // Switches appends over to `new_gen` and returns the final length of the old generation.
+    fn roll_over(&mut self, new_gen: u64) -> Result<u64> {
+        let old_gen = self.current_gen;
+        self.generations.add_generation(new_gen)?;
+        let writer = new_log_file(&self.dir, new_gen)?;
+        let mut file = self.log.file.lock().unwrap();
+        // The sealed generation must be as durable as the writes acknowledged from it.
+        file.writer.flush()?;
+        if matches!(self.options.durability, Durability::Fsync | Durability::GroupCommit { .. }) {
+            file.writer.get_ref().sync_data()?;
+        }
+        let len = file.writer.pos;
+        file.writer = writer;
+        self.log.flushed_seq.store(file.end_seq, Ordering::SeqCst);
+        drop(file);
+
+        self.current_gen = new_gen;
+        if let Some(archive) = &self.options.archive_dir {
+            archive_file(&log_path(&self.dir, old_gen), &log_path(archive, old_gen))?;
+        }
+        Ok(len)
+    }
+
+    // Collects a finished compaction and reports its outcome.
+    fn reap_compaction(&mut self) -> Result<()> {
+        match &self.compaction {
+            Some(Compaction { handle: Some(handle), .. }) if handle.is_finished() => {}
+            _ => return Ok(()),
+        }
+
+        self.finish_compaction()
+    }
+
+    // Waits for the running compaction, unless `KvStore::compact` is already waiting for it.
+    fn finish_compaction(&mut self) -> Result<()> {
+        let (handle, started) = match &mut self.compaction {
+            Some(compaction) => (compaction.handle.take(), compaction.started),
+            None => return Ok(()),
+        };
+
+        let result = match handle {
+            Some(handle) => join_compaction(&self.generations, handle),
+            None => return Ok(()),
+        };
+
+        self.compaction = None;
+        self.complete_compaction(started, result.is_ok());
+        result
+    }
+
+    // Counts the log again, since writes kept going while compaction ran. Everything on disk
+    // that the index does not point at is stale.
+    fn complete_compaction(&mut self, started: Instant, succeeded: bool) {
+        if succeeded {
+            self.last_compaction = Some(CompactionStats {
+                finished_at: now_millis(),
+                duration: started.elapsed(),
+            });
+        }
+        self.live_data_size = self.index.read().unwrap()
+            .values()
+            .map(CommandPosition::data_size)
+            .sum();
+
+        let gens = self.generations.live_generations();
+        let active_len = self.log.file.lock().unwrap().writer.pos;
+        let total: u64 = gens.iter()
+            .map(|&gen| if gen == self.current_gen {
+                active_len
+            } else {
+                fs::metadata(log_path(&self.dir, gen)).map_or(0, |metadata| metadata.len())
+            })
+            .map(|len| len.saturating_sub(LOG_HEADER_SIZE))
+            .sum::<u64>() + self.generations.blob_size();
+        self.stale_data_size = total.saturating_sub(self.live_data_size);
+        self.gen_count = gens.len();
+    }
+
+    // Makes the records appended so far as durable as the options ask for. Group commit syncs
+    // outside the writer lock, see `KvStore::write`.
+    fn write_through(&mut self) -> Result<()> {
+        match self.options.durability {
+            Durability::Buffered | Durability::GroupCommit { .. } => Ok(()),
+            Durability::Flush => self.log.flush(),
+            Durability::Fsync => self.log.sync().map(|_| ()),
+        }
+    }
+
+    fn append(&mut self, cmd: &Command) -> Result<CommandPosition> {
+        let cmd_pos = self.append_record(cmd)?;
+        self.write_through()?;
+        Ok(cmd_pos)
+    }
+
+    // Large values go to a blob file first, and the log only gets a record pointing at it.
+    fn append_record(&mut self, cmd: &Command) -> Result<CommandPosition> {
+        let seq = self.next_seq;
+        let blob = match cmd {
+            Command::Set { key, value } | Command::SetWithTtl { key, value, .. }
+                if value.len() as u64 >= self.options.blob_threshold => {
+                let sync = matches!(self.options.durability, Durability::Fsync | Durability::GroupCommit { .. });
+                write_blob_file(&self.dir, seq, value, sync)?;
+                if let Some(archive) = &self.options.archive_dir {
+                    archive_file(&blob_path(&self.dir, seq), &blob_path(archive, seq))?;
+                }
+                Some(Command::SetBlob {
+                    key: key.clone(),
+                    len: value.len() as u64,
+                    expires_at: cmd.expires_at(),
+                })
+            }
+            _ => None,
+        };
+
+        self.write_command(seq, Some(now_millis()), blob.as_ref().unwrap_or(cmd))
+    }
+
+    // Logs `cmd` as written, under a sequence number above every earlier one.
+    fn write_command(&mut self, seq: u64, timestamp: Option<u64>, cmd: &Command) -> Result<CommandPosition> {
+        let mut file = self.log.file.lock().unwrap();
+        let log_start_pos = file.writer.pos;
+        let size = write_record(&mut file.writer, seq, timestamp, cmd)?;
+        file.end_seq = seq + 1;
+        drop(file);
+        self.next_seq = seq + 1;
+
+        let blob_len = match *cmd {
+            Command::SetBlob { len, .. } => {
+                self.generations.add_blob(self.current_gen, seq, len);
+                Some(len)
+            }
+            _ => None,
+        };
+        Ok(CommandPosition {
             log_start_pos,
             size,
             gen: self.current_gen,
-        }.into());
-        if let Some(val) = val {
-            self.stale_data_size += val.size;
+            seq,
+            expires_at: cmd.expires_at(),
+            blob_len,
+        })
+    }
+
+    // Appends the writes of an archived generation the store does not have yet, along with
+    // their blobs, keeping their sequence numbers and timestamps.
+    fn replay_archived(&mut self, archive: &Path, gen: u64, point: RestorePoint) -> Result<()> {
+        let mut reader = LogReader::open(&log_path(archive, gen))?;
+        read_committed(&mut reader, 0, |seq, entry| point.includes(seq, entry.timestamp), |seq, entry| {
+            if seq < self.next_seq {
+                return Ok(());
+            }
+            match entry.command {
+                Command::SetBlob { .. } => {
+                    link_or_copy(&blob_path(archive, seq), &blob_path(&self.dir, seq))?;
+                }
+                Command::SeqMark => {
+                    self.next_seq = seq + 1;
+                    return Ok(());
+                }
+                _ => {}
+            }
+
+            let cmd_pos = self.write_command(seq, entry.timestamp, &entry.command)?;
+            let index = Arc::clone(&self.index);
+            self.index_command(&mut index.write().unwrap(), entry.command, cmd_pos);
+            self.maintain()
+        })
+    }
+}
+
+impl Drop for KvStoreWriter {
+    // On clean shutdown the active generation is sealed, so it gets a hint file as well.
+    fn drop(&mut self) {
+        let _ = self.finish_compaction();
+
+        let mut file = self.log.file.lock().unwrap();
+        if file.writer.flush().is_ok() {
+            let _ = write_hint_file(&self.dir, self.current_gen, file.writer.pos, &self.active_hints);
         }
+    }
+}
+
+fn compact(
+    generations: &Generations,
+    index: &RwLock<Index>,
+    snapshot: Vec<(Vec<u8>, CommandPosition)>,
+    last_seq: Option<u64>,
+    compaction_gen: u64,
+    mut compaction_writer: BufWriterWithPos<File>) -> Result<()> {
+    let dir = generations.dir.as_path();
+    let mut readers: HashMap<u64, LogReader> = HashMap::new();
+    let mut moved = Vec::with_capacity(snapshot.len());
+    for (key, cmd_pos) in snapshot {
+        let reader = match readers.entry(cmd_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(LogReader::open(&log_path(dir, cmd_pos.gen))?),
+        };
+        let record = reader.read_record(cmd_pos.log_start_pos, cmd_pos.size)?;
 
-        if self.stale_data_size >= COMPACTION_THRESHOLD {
-            self.compact_log()?;
+        let log_start_pos = compaction_writer.pos;
+        let size = write_record(&mut compaction_writer, cmd_pos.seq, record.timestamp, &record.command)?;
+        if let Some(len) = cmd_pos.blob_len {
+            generations.add_blob(compaction_gen, cmd_pos.seq, len);
         }
+        moved.push((key, cmd_pos, CommandPosition {
+            log_start_pos,
+            size,
+            gen: compaction_gen,
+            seq: cmd_pos.seq,
+            expires_at: cmd_pos.expires_at,
+            blob_len: cmd_pos.blob_len,
+        }));
+    }
 
-        Ok(())
+    if let Some(seq) = last_seq {
+        write_record(&mut compaction_writer, seq, Some(now_millis()), &Command::SeqMark)?;
+    }
+
+    // The old generations are deleted below, so the copy has to be on disk first.
+    compaction_writer.flush()?;
+    compaction_writer.get_ref().sync_all()?;
+    let mut hints: Vec<_> = moved.iter()
+        .map(|(key, _, new_pos)| HintEntry::Set {
+            key: key.clone(),
+            gen: compaction_gen,
+            pos: new_pos.log_start_pos,
+            size: new_pos.size,
+            seq: new_pos.seq,
+            expires_at: new_pos.expires_at,
+            blob_len: new_pos.blob_len,
+        })
+        .collect();
+    hints.extend(last_seq.map(|seq| HintEntry::SeqMark { seq }));
+    write_hint_file(dir, compaction_gen, compaction_writer.pos, &hints)?;
+
+    seal_log_file(dir, compaction_gen)?;
+
+    let replaced = generations.commit_compaction(true)?;
+    move_positions(index, moved);
+    generations.retire(&replaced)
+}
+
+// Waits for a compaction thread. One that panicked never got to abort its compaction, so that
+// is done here, which deletes the output as well.
+fn join_compaction(generations: &Generations, handle: JoinHandle<Result<()>>) -> Result<()> {
+    match handle.join() {
+        Ok(result) => result,
+        Err(_) => {
+            generations.abort_compaction()?;
+            Err(KvError::Unknown)
+        }
     }
+}
+
+// Copies the live records of some sealed segments into a new generation. Tombstones are kept
+// unless the key was written again since, as an older segment may still hold a value they hide.
+// Loading resolves records by sequence number, so the merged generation can sit after segments
+// with newer records.
+fn merge(
+    generations: &Generations,
+    index: &RwLock<Index>,
+    segments: Vec<u64>,
+    merge_gen: u64,
+    mut merge_writer: BufWriterWithPos<File>) -> Result<()> {
+    let dir = generations.dir.as_path();
+    let mut moved = Vec::new();
+    let mut hints = Vec::new();
+    for gen in segments {
+        let file_path = log_path(dir, gen);
+        let mut reader = LogReader::open(&file_path)?;
+        let entries = match read_hint_file(dir, gen, fs::metadata(&file_path)?.len())? {
+            Some(entries) => entries,
+            None => replay(gen, &mut reader, 0)?,
+        };
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.index.get(&key) {
-            Some(cmd_pos) => {
-                let reader = self.readers
-                    .get_mut(&cmd_pos.gen)
-                    .ok_or(KvError::KeyNotFound)?;
-                reader.seek(SeekFrom::Start(cmd_pos.log_start_pos))?;
-                let cmd_reader = reader.take(cmd_pos.size);
-                match serde_json::from_reader(cmd_reader)? {
-                    Command::Set { value, .. } => {
-                        return Ok(Some(value));
+        for entry in entries {
+            match entry {
+                HintEntry::Set { key, pos, size, seq, expires_at, blob_len, .. } => {
+                    let old_pos = match index.read().unwrap().get(&key) {
+                        Some(cmd_pos) if cmd_pos.gen == gen && cmd_pos.log_start_pos == pos => *cmd_pos,
+                        _ => continue,
+                    };
+                    let record = reader.read_record(pos, size)?;
+                    let log_start_pos = merge_writer.pos;
+                    let size = write_record(&mut merge_writer, seq, record.timestamp, &record.command)?;
+                    if let Some(len) = blob_len {
+                        generations.add_blob(merge_gen, seq, len);
                     }
-                    _ => {
-                        Err(KvError::UnexpectedCommandType)
+                    hints.push(HintEntry::Set { key: key.clone(), gen: merge_gen, pos: log_start_pos, size, seq, expires_at, blob_len });
+                    moved.push((key, old_pos, CommandPosition {
+                        log_start_pos,
+                        size,
+                        gen: merge_gen,
+                        seq,
+                        expires_at,
+                        blob_len,
+                    }));
+                }
+                HintEntry::Remove { key, seq, .. } => {
+                    if index.read().unwrap().contains_key(&key) {
+                        continue;
                     }
+                    let cmd = Command::remove(key.clone());
+                    let size = write_record(&mut merge_writer, seq, None, &cmd)?;
+                    hints.push(HintEntry::Remove { key, size, seq });
                 }
+                // Only a full compaction may claim every older generation is replaced.
+                HintEntry::SeqMark { .. } => {}
             }
-            None => Ok(None)
         }
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key.clone());
-            let log_start_pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+    let keep_output = !hints.is_empty();
+    if keep_output {
+        merge_writer.flush()?;
+        merge_writer.get_ref().sync_all()?;
+        write_hint_file(dir, merge_gen, merge_writer.pos, &hints)?;
+        seal_log_file(dir, merge_gen)?;
+    }
+
+    let merged = generations.commit_compaction(keep_output)?;
+    if !keep_output {
+        drop(merge_writer);
+        remove_generation(dir, merge_gen)?;
+    }
+    move_positions(index, moved);
+    generations.retire(&merged)
+}
 
-            let log_end_pos = self.writer.pos;
+// Bytes of the tombstones a merge would keep.
+fn tombstone_size(index: &Index, hints: &[HintEntry]) -> u64 {
+    hints.iter()
+        .map(|hint| match hint {
+            HintEntry::Remove { key, size, .. } if !index.contains_key(key) => *size,
+            _ => 0,
+        })
+        .sum()
+}
 
-            let val = self.index.remove(&key);
-            if let Some(old_cmd) = val {
-                self.stale_data_size += old_cmd.size;
+// Keys written or removed while compacting already point past the compacted generations.
+fn move_positions(index: &RwLock<Index>, moved: Vec<(Vec<u8>, CommandPosition, CommandPosition)>) {
+    let mut index = index.write().unwrap();
+    for (key, old_pos, new_pos) in moved {
+        if let Some(cmd_pos) = index.get_mut(&key) {
+            if cmd_pos.gen == old_pos.gen && cmd_pos.log_start_pos == old_pos.log_start_pos {
+                *cmd_pos = new_pos;
             }
+        }
+    }
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+// Advisory lock on the data directory, held for as long as the store is open. A store that
+// writes needs it exclusively, read-only stores share it. Read-only stores never create the
+// LOCK file, so a directory no store has written to yet, like a checkpoint, is not locked.
+// That gap is deliberate: a read-only open must work on a directory it cannot write to, and a
+// writer that shows up later creates LOCK and takes it without seeing the reader.
+fn lock_dir(dir: &Path, read_only: bool) -> Result<Option<File>> {
+    let file = if read_only {
+        match File::open(dir.join("LOCK")) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join("LOCK"))?
+    };
+    let locked = if read_only { file.try_lock_shared() } else { file.try_lock() };
+    match locked {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Err(KvError::Locked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+fn retired_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.retired", gen))
+}
+
+// A snapshot can still read from a generation that got retired after it was taken.
+fn open_generation(dir: &Path, gen: u64) -> Result<LogReader> {
+    match LogReader::open(&log_path(dir, gen)) {
+        Err(KvError::Io(e)) if e.kind() == io::ErrorKind::NotFound => LogReader::open(&retired_path(dir, gen)),
+        result => result,
+    }
+}
+
+// Where the log of a pinned generation is, retired or not.
+fn generation_path(dir: &Path, gen: u64) -> PathBuf {
+    let path = log_path(dir, gen);
+    if path.exists() {
+        path
+    } else {
+        retired_path(dir, gen)
+    }
+}
+
+fn link_or_copy(source: &Path, target: &Path) -> Result<()> {
+    if fs::hard_link(source, target).is_err() {
+        fs::copy(source, target)?;
+    }
+    Ok(())
+}
 
-            let remove_cmd_size = log_end_pos - log_start_pos;
-            self.stale_data_size += remove_cmd_size;
+// Replaces whatever an earlier attempt left in the archive, a copy may have been cut short.
+fn archive_file(source: &Path, target: &Path) -> Result<()> {
+    match fs::remove_file(target) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    link_or_copy(source, target)
+}
+
+// Nothing is ever appended to a sealed generation again.
+fn seal_log_file(dir: &Path, gen: u64) -> Result<()> {
+    let path = log_path(dir, gen);
+    let mut permissions = fs::metadata(&path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
 
-            if self.stale_data_size >= COMPACTION_THRESHOLD {
-                self.compact_log()?;
+// Finishes what a crash interrupted. A compaction whose output got its hint file was done
+// writing and is rolled forward, any other is rolled back. Logs the manifest does not list are
+// left over from either and get deleted, unless the store is read-only.
+fn recover_manifest(dir: &Path, mut manifest: Manifest, read_only: bool) -> Result<Manifest> {
+    if let Some(compaction) = manifest.compaction.take() {
+        let complete = match fs::metadata(log_path(dir, compaction.output)) {
+            Ok(metadata) => read_hint_file(dir, compaction.output, metadata.len())?.is_some(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        if complete {
+            for gen in &compaction.inputs {
+                manifest.generations.remove(gen);
             }
+            manifest.generations.insert(compaction.output);
+        }
+    }
 
-            Ok(())
-        } else {
-            Err(KvError::KeyNotFound)
+    if !read_only {
+        for gen in get_sorted_gens(dir)? {
+            if !manifest.generations.contains(&gen) {
+                warn!("removing generation {} left over by an interrupted compaction", gen);
+                remove_generation(dir, gen)?;
+            }
         }
     }
+    Ok(manifest)
 }
 
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.log", gen))
+// Files `open` leaves alone are expected, the leftovers it would clean up are orphaned.
+fn check_file(name: String, is_dir: bool, gens: &[u64], blobs: &HashMap<u64, u64>) -> Option<Problem> {
+    if is_dir {
+        return (name != QUARANTINE_DIR).then_some(Problem::UnexpectedFile { name });
+    }
+    let (stem, extension) = match name.as_str() {
+        "LOCK" | "MANIFEST" | "engine" => return None,
+        "MANIFEST.tmp" => return Some(Problem::OrphanedFile { name }),
+        name => name.split_once('.').unwrap_or((name, "")),
+    };
+    let Ok(number) = stem.parse::<u64>() else {
+        return Some(Problem::UnexpectedFile { name });
+    };
+    match extension {
+        "log" => None,
+        "hint" if gens.contains(&number) => None,
+        "blob" if blobs.contains_key(&number) => None,
+        "hint" | "blob" | "log.retired" | "hint.tmp" => Some(Problem::OrphanedFile { name }),
+        _ => Some(Problem::UnexpectedFile { name }),
+    }
+}
+
+fn remove_retired_generations(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("retired".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// Only the hint file may be left when an earlier attempt was cut short.
+fn remove_generation(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(log_path(dir, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    remove_hint_file(dir, gen)
 }
 
 fn get_sorted_gens(dir: &Path) -> Result<Vec<u64>> {
@@ -199,56 +2008,201 @@ fn get_sorted_gens(dir: &Path) -> Result<Vec<u64>> {
     Ok(gens)
 }
 
-fn load(gen: u64, index: &mut BTreeMap<String, CommandPosition>, reader: &mut BufReaderWithPos<File>) -> Result<u64> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut cmd_stream = Deserializer::from_reader(reader)
-        .into_iter::<Command>();
+// Reads a generation from the log.
+fn replay(gen: u64, reader: &mut LogReader, next_seq: u64) -> Result<Vec<HintEntry>> {
+    let mut hints = Vec::new();
+    replay_into(gen, reader, next_seq, &mut hints)?;
+    Ok(hints)
+}
+
+// Like `replay`, but `hints` keeps what was read before an error.
+fn replay_into(gen: u64, reader: &mut LogReader, next_seq: u64, hints: &mut Vec<HintEntry>) -> Result<()> {
+    read_committed(reader, next_seq, |_, _| true, |seq, entry| {
+        hints.extend(hint_of(gen, seq, entry));
+        Ok(())
+    })
+}
+
+fn hint_of(gen: u64, seq: u64, entry: LogEntry) -> Option<HintEntry> {
+    Some(match entry.command {
+        Command::Set {key, ..} => HintEntry::Set {
+            key,
+            gen,
+            pos: entry.pos,
+            size: entry.size,
+            seq,
+            expires_at: None,
+            blob_len: None,
+        },
+        Command::SetWithTtl {key, expires_at, ..} => HintEntry::Set {
+            key,
+            gen,
+            pos: entry.pos,
+            size: entry.size,
+            seq,
+            expires_at: Some(expires_at),
+            blob_len: None,
+        },
+        Command::SetBlob {key, len, expires_at} => HintEntry::Set {
+            key,
+            gen,
+            pos: entry.pos,
+            size: entry.size,
+            seq,
+            expires_at,
+            blob_len: Some(len),
+        },
+        Command::Remove {key} => HintEntry::Remove {
+            key,
+            size: entry.size,
+            seq,
+        },
+        Command::SeqMark => HintEntry::SeqMark { seq },
+        Command::BatchBegin { .. } | Command::BatchCommit => return None,
+    })
+}
+
+// Visits the records of a log that count, in order. Legacy JSON records get sequence numbers
+// in log order. The commands of a batch only count once its commit marker follows them; a
+// batch cut short by a crash, or followed by anything else, is dropped. So is everything from
+// the first record `include` turns down on, a batch it cuts in two included.
+fn read_committed(
+    reader: &mut LogReader,
+    mut next_seq: u64,
+    mut include: impl FnMut(u64, &LogEntry) -> bool,
+    mut visit: impl FnMut(u64, LogEntry) -> Result<()>) -> Result<()> {
+    let mut batches = Batches::default();
+    let mut stopped = false;
+    reader.read_entries(|entry: LogEntry| {
+        let seq = entry.seq.unwrap_or(next_seq);
+        next_seq = next_seq.max(seq + 1);
+        stopped = stopped || !include(seq, &entry);
+        if stopped {
+            return Ok(());
+        }
+        batches.push(seq, entry, &mut visit)
+    })
+}
+
+// Holds back the commands of a batch until its commit marker shows up.
+#[derive(Default)]
+struct Batches {
+    open: Option<(usize, Vec<(u64, LogEntry)>)>,
+}
+
+impl Batches {
+    fn push(&mut self, seq: u64, entry: LogEntry, visit: &mut impl FnMut(u64, LogEntry) -> Result<()>) -> Result<()> {
+        match entry.command {
+            Command::BatchBegin {count} => {
+                self.open = Some((count as usize, Vec::new()));
+            }
+            Command::BatchCommit => {
+                if let Some((count, entries)) = self.open.take() {
+                    if entries.len() == count {
+                        for (seq, entry) in entries {
+                            visit(seq, entry)?;
+                        }
+                    }
+                }
+            }
+            _ => match &mut self.open {
+                Some((count, entries)) if entries.len() < *count => entries.push((seq, entry)),
+                _ => {
+                    self.open = None;
+                    visit(seq, entry)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // A batch that lost some of its records never commits.
+    fn interrupt(&mut self) {
+        self.open = None;
+    }
+}
+
+// Records are resolved by sequence number rather than by generation, since a merged segment
+// gets a newer generation than segments holding newer records. `removed` keeps the latest
+// tombstone of every key, so a value it hides loses even when it is loaded later.
+fn load(index: &mut Index, removed: &mut HashMap<Vec<u8>, u64>, hints: Vec<HintEntry>, next_seq: &mut u64) -> u64 {
     let mut stale_data_size: u64 = 0;
-    while let Some(cmd_res) = cmd_stream.next() {
-        let new_pos = cmd_stream.byte_offset() as u64;
-        let cmd = cmd_res?;
-        match cmd.clone() {
-            Command::Set {key, ..} => {
-                let size = new_pos - pos;
+    for hint in hints {
+        match hint {
+            HintEntry::Set {key, gen, pos, size, seq, expires_at, blob_len} => {
+                *next_seq = (*next_seq).max(seq + 1);
+                let newer = removed.get(&key).is_some_and(|&removed_seq| removed_seq > seq)
+                    || index.get(&key).is_some_and(|cmd_pos| cmd_pos.seq >= seq);
+                if newer {
+                    stale_data_size += size;
+                    continue;
+                }
+
                 let val = index.insert(key, CommandPosition{
                     log_start_pos: pos,
                     size,
-                    gen
+                    gen,
+                    seq,
+                    expires_at,
+                    blob_len,
                 });
                 if let Some(old_cmd) = val {
                     stale_data_size+= old_cmd.size;
                 }
             }
-            Command::Remove {key} => {
-                let val = index.remove(&key);
-                if let Some(old_cmd) = val {
-                    stale_data_size+= old_cmd.size;
+            HintEntry::Remove {key, size, seq} => {
+                *next_seq = (*next_seq).max(seq + 1);
+                if index.get(&key).is_some_and(|cmd_pos| cmd_pos.seq < seq) {
+                    if let Some(old_cmd) = index.remove(&key) {
+                        stale_data_size+= old_cmd.size;
+                    }
                 }
+                let removed_seq = removed.entry(key).or_default();
+                *removed_seq = (*removed_seq).max(seq);
 
-                let remove_cmd_size: u64 = new_pos - pos;
-                stale_data_size += remove_cmd_size;
+                stale_data_size += size;
+            }
+            HintEntry::SeqMark {seq} => {
+                *next_seq = (*next_seq).max(seq + 1);
             }
         }
+    }
+
+    stale_data_size
+}
+
+fn recover_log_tail(file_path: &Path) -> Result<()> {
+    let valid_len = match LogReader::open(file_path) {
+        Ok(mut reader) => reader.valid_len()?,
+        Err(e) if e.is_corruption() => 0,
+        Err(e) => return Err(e),
+    };
 
-        pos = new_pos;
+    let len = fs::metadata(file_path)?.len();
+    if valid_len < len {
+        warn!("dropping {} bytes of torn or corrupted records at offset {} of {}",
+            len - valid_len, valid_len, file_path.display());
+        let file = OpenOptions::new()
+            .write(true)
+            .open(file_path)?;
+        file.set_len(valid_len)?;
+        file.sync_all()?;
     }
 
-    Ok(stale_data_size)
+    Ok(())
 }
 
-fn new_log_file(dir: &PathBuf, gen: u64, readers: &mut HashMap<u64, BufReaderWithPos<File>>)-> Result<BufWriterWithPos<File>> {
-        let file_path = log_path(dir, gen);
+fn new_log_file(dir: &Path, gen: u64)-> Result<BufWriterWithPos<File>> {
         let write_file = OpenOptions::new()
-            .write(true)
             .create(true)
             .append(true)
-            .open(&file_path)?;
+            .open(log_path(dir, gen))?;
 
-        let read_file = OpenOptions::new()
-            .read(true)
-            .open(&file_path)?;
-        let reader = BufReaderWithPos::new(read_file)?;
-        readers.insert(gen, reader);
+        let mut writer = BufWriterWithPos::new(write_file)?;
+        if writer.pos == 0 {
+            write_log_header(&mut writer)?;
+            writer.flush()?;
+        }
 
-        return Ok(BufWriterWithPos::new(write_file)?)
-}
\ No newline at end of file
+        Ok(writer)
+}
@@ -1,9 +1,12 @@
 use std::error::Error;
 use std::io::{BufReader, BufWriter, Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
 use crate::err;
-use crate::KvError::{KeyNotFound, Unknown};
+use crate::engine::into_string;
+use crate::KvError::{ConditionFailed, KeyNotFound, Server, Unknown};
 use crate::message::{Request, Response};
+use crate::stats::EngineStats;
 use crate::net::{read_message, write_message};
 
 
@@ -25,6 +28,37 @@ impl KvClient {
     }
 
     pub fn get(&mut self, key: String) -> err::Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(into_string(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> err::Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn remove(&mut self, key: String) -> err::Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> err::Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> err::Result<()> {
+        self.compare_and_swap_bytes(key.into_bytes(), expected.map(String::into_bytes), new.map(String::into_bytes))
+    }
+
+    pub fn set_if_absent(&mut self, key: String, value: String) -> err::Result<()> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn set_if_present(&mut self, key: String, value: String) -> err::Result<()> {
+        self.set_if_present_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> err::Result<Option<Vec<u8>>> {
         write_message(&mut self.writer, Request::Get {
             key
         })?;
@@ -39,13 +73,13 @@ impl KvClient {
             Response::ErrorKeyNotFound => {
                 Err(KeyNotFound)
             }
-            Response::ErrorUnknown{..} => {
+            Response::ErrorUnknown{..} | Response::ErrorConditionFailed | Response::OkStats{..} => {
                 Err(Unknown)
             }
         }
     }
 
-    pub fn set(&mut self, key: String, value: String) -> err::Result<()> {
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> err::Result<()> {
         write_message(&mut self.writer, Request::Set {
             key,
             value
@@ -61,13 +95,13 @@ impl KvClient {
             Response::ErrorKeyNotFound => {
                 Err(Unknown)
             }
-            Response::ErrorUnknown{..} => {
+            Response::ErrorUnknown{..} | Response::ErrorConditionFailed | Response::OkStats{..} => {
                 Err(Unknown)
             }
         }
     }
 
-    pub fn remove(&mut self, key: String) -> err::Result<()> {
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> err::Result<()> {
         write_message(&mut self.writer, Request::Remove {
             key
         })?;
@@ -82,7 +116,103 @@ impl KvClient {
             Response::ErrorKeyNotFound => {
                 Err(KeyNotFound)
             }
-            Response::ErrorUnknown{..} => {
+            Response::ErrorUnknown{..} | Response::ErrorConditionFailed | Response::OkStats{..} => {
+                Err(Unknown)
+            }
+        }
+    }
+
+    pub fn set_bytes_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> err::Result<()> {
+        write_message(&mut self.writer, Request::SetWithTtl {
+            key,
+            value,
+            ttl_ms: ttl.as_millis() as u64,
+        })?;
+        let response = read_message::<Response>(&mut self.reader)?;
+        match response {
+            Response::OkValue{ .. } => {
+                Err(Unknown)
+            }
+            Response::OkNoContent => {
+                Ok(())
+            }
+            Response::ErrorKeyNotFound => {
+                Err(Unknown)
+            }
+            Response::ErrorUnknown{..} | Response::ErrorConditionFailed | Response::OkStats{..} => {
+                Err(Unknown)
+            }
+        }
+    }
+
+    // The checkpoint goes to the directory `name` within the server's checkpoint directory.
+    pub fn checkpoint(&mut self, name: String) -> err::Result<()> {
+        write_message(&mut self.writer, Request::Checkpoint {
+            name
+        })?;
+        let response = read_message::<Response>(&mut self.reader)?;
+        match response {
+            Response::OkNoContent => {
+                Ok(())
+            }
+            Response::ErrorUnknown { message } => {
+                Err(Server(message))
+            }
+            _ => {
+                Err(Unknown)
+            }
+        }
+    }
+
+    pub fn stats(&mut self) -> err::Result<EngineStats> {
+        write_message(&mut self.writer, Request::Stats)?;
+        let response = read_message::<Response>(&mut self.reader)?;
+        match response {
+            Response::OkStats { stats } => {
+                Ok(stats)
+            }
+            Response::ErrorUnknown { message } => {
+                Err(Server(message))
+            }
+            _ => {
+                Err(Unknown)
+            }
+        }
+    }
+
+    pub fn compare_and_swap_bytes(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> err::Result<()> {
+        self.conditional_write(Request::CompareAndSwap {
+            key,
+            expected,
+            new
+        })
+    }
+
+    pub fn set_if_absent_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> err::Result<()> {
+        self.conditional_write(Request::SetIfAbsent {
+            key,
+            value
+        })
+    }
+
+    pub fn set_if_present_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> err::Result<()> {
+        self.conditional_write(Request::SetIfPresent {
+            key,
+            value
+        })
+    }
+
+    fn conditional_write(&mut self, request: Request) -> err::Result<()> {
+        write_message(&mut self.writer, request)?;
+        let response = read_message::<Response>(&mut self.reader)?;
+        match response {
+            Response::OkNoContent => {
+                Ok(())
+            }
+            Response::ErrorConditionFailed => {
+                Err(ConditionFailed)
+            }
+            _ => {
                 Err(Unknown)
             }
         }
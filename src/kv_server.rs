@@ -3,28 +3,40 @@ use std::env::current_dir;
 use std::error::Error;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use slog::{Logger};
+use crate::durability::Durability;
 use crate::engine::{KvsEngine, EngineType, Sled};
 use crate::{error, info, KvError};
 use crate::kv::KvStore;
+use crate::options::KvStoreOptions;
 use crate::message::{Request, Response};
 use crate::net::{MsgError, read_message, write_message};
 
 pub struct KvServer {
     logger: Logger,
-    engine: Arc<Mutex<Box<dyn KvsEngine + Send>>>,
+    engine: Arc<dyn KvsEngine>,
+    checkpoint_dir: Option<Arc<PathBuf>>,
 }
 
 impl KvServer {
-    pub fn new(logger: Logger, engine_type: EngineType) -> Result<KvServer, Box<dyn Error>> {
+    pub fn new(logger: Logger, engine_type: EngineType, durability: Durability) -> Result<KvServer, Box<dyn Error>> {
         Ok(KvServer {
             logger,
-            engine: Arc::new(Mutex::new(new_engine(engine_type)?)),
+            engine: Arc::from(new_engine(engine_type, durability)?),
+            checkpoint_dir: None,
         })
     }
 
+    /// Lets clients write checkpoints, each to a new directory within `dir`. Checkpoints are
+    /// turned down until this is set.
+    pub fn checkpoint_dir(mut self, dir: PathBuf) -> Self {
+        self.checkpoint_dir = Some(Arc::new(dir));
+        self
+    }
+
     pub fn start<Addr: ToSocketAddrs>(&mut self, address: Addr) -> io::Result<()> {
         let listener = TcpListener::bind(address)?;
 
@@ -35,8 +47,9 @@ impl KvServer {
 
                     let logger = self.logger.clone();
                     let engine = Arc::clone(&self.engine);
+                    let checkpoint_dir = self.checkpoint_dir.clone();
                     thread::spawn(move || {
-                        Self::handle_connection(&logger, engine, stream)
+                        Self::handle_connection(&logger, engine, checkpoint_dir.as_deref(), stream)
                     });
                 }
                 Err(err) => {
@@ -48,29 +61,23 @@ impl KvServer {
         Ok(())
     }
 
-    fn handle_connection(logger: &Logger, engine: Arc<Mutex<Box<dyn KvsEngine + Send>>>, stream: TcpStream) {
+    fn handle_connection(logger: &Logger, engine: Arc<dyn KvsEngine>, checkpoint_dir: Option<&PathBuf>, stream: TcpStream) {
         let mut reader = BufReader::new(&stream);
         let mut writer = BufWriter::new(&stream);
         loop {
             match read_message::<Request>(&mut reader) {
                 Ok(request) => {
-                    let _ = Self::handle_request(logger, Arc::clone(&engine), &mut writer, request)
+                    let _ = Self::handle_request(logger, Arc::clone(&engine), checkpoint_dir, &mut writer, request)
                         .inspect_err(|e| {
                             error!(logger, "{}", e)
                         });
                 }
                 Err(err) => {
-                    match err {
-                        MsgError::Io(ref err) => {
-                            match err.kind() {
-                                io::ErrorKind::UnexpectedEof => {
-                                    info!(logger, "client disconnect {:?}", stream.peer_addr());
-                                    return;
-                                }
-                                _ => {}
-                            }
+                    if let MsgError::Io(ref err) = err {
+                        if err.kind() == io::ErrorKind::UnexpectedEof {
+                            info!(logger, "client disconnect {:?}", stream.peer_addr());
+                            return;
                         }
-                        _ => {}
                     }
 
                     error!(logger, "{}", err);
@@ -81,21 +88,23 @@ impl KvServer {
 
     fn handle_request(
         logger: &Logger,
-        engine: Arc<Mutex<Box<dyn KvsEngine + Send>>>,
+        engine: Arc<dyn KvsEngine>,
+        checkpoint_dir: Option<&PathBuf>,
         writer: &mut dyn Write,
         request: Request) -> Result<(), Box<dyn Error>> {
         match request {
             Request::Get { key } => {
-                match engine.lock().unwrap().get(key.clone()) {
+                let key_str = String::from_utf8_lossy(&key);
+                match engine.get_bytes(&key) {
                     Ok(value) => {
-                        info!(logger, "get {} {:?}", key, value);
+                        info!(logger, "get {} {:?}", key_str, value.as_deref().map(String::from_utf8_lossy));
                         let res = Response::OkValue {
                             value
                         };
                         write_message::<Response>(writer, res)?;
                     }
                     Err(e) => {
-                        error!(logger, "get {} {}", key, e);
+                        error!(logger, "get {} {}", key_str, e);
                         write_message::<Response>(writer, Response::ErrorUnknown {
                             message: e.to_string()
                         })?;
@@ -103,28 +112,89 @@ impl KvServer {
                 }
             }
             Request::Set { key, value } => {
-                match engine.lock().unwrap().set(key.clone(), value.clone()) {
+                let key_str = String::from_utf8_lossy(&key).into_owned();
+                let value_str = String::from_utf8_lossy(&value).into_owned();
+                match engine.set_bytes(key, value) {
+                    Ok(_) => {
+                        info!(logger, "set {} {}", key_str, value_str);
+                        write_message::<Response>(writer, Response::OkNoContent)?;
+                    }
+                    Err(e) => {
+                        error!(logger, "set {} {} {}", key_str, value_str, e);
+                        write_message::<Response>(writer, Response::ErrorUnknown {
+                            message: e.to_string()
+                        })?;
+                    }
+                }
+            }
+            Request::SetWithTtl { key, value, ttl_ms } => {
+                let key_str = String::from_utf8_lossy(&key).into_owned();
+                let value_str = String::from_utf8_lossy(&value).into_owned();
+                match engine.set_bytes_with_ttl(key, value, Duration::from_millis(ttl_ms)) {
                     Ok(_) => {
-                        info!(logger, "set {} {}", key, value);
+                        info!(logger, "set {} {} ttl {}ms", key_str, value_str, ttl_ms);
                         write_message::<Response>(writer, Response::OkNoContent)?;
                     }
                     Err(e) => {
-                        error!(logger, "set {} {} {}", key, value, e);
+                        error!(logger, "set {} {} ttl {}ms {}", key_str, value_str, ttl_ms, e);
+                        write_message::<Response>(writer, Response::ErrorUnknown {
+                            message: e.to_string()
+                        })?;
+                    }
+                }
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                let key_str = String::from_utf8_lossy(&key).into_owned();
+                let result = engine.compare_and_swap_bytes(key, expected, new);
+                Self::write_conditional_response(logger, writer, "cas", &key_str, result)?;
+            }
+            Request::SetIfAbsent { key, value } => {
+                let key_str = String::from_utf8_lossy(&key).into_owned();
+                let result = engine.set_if_absent_bytes(key, value);
+                Self::write_conditional_response(logger, writer, "set-if-absent", &key_str, result)?;
+            }
+            Request::SetIfPresent { key, value } => {
+                let key_str = String::from_utf8_lossy(&key).into_owned();
+                let result = engine.set_if_present_bytes(key, value);
+                Self::write_conditional_response(logger, writer, "set-if-present", &key_str, result)?;
+            }
+            Request::Stats => {
+                match engine.stats() {
+                    Ok(stats) => {
+                        info!(logger, "stats");
+                        write_message::<Response>(writer, Response::OkStats { stats })?;
+                    }
+                    Err(e) => {
+                        error!(logger, "stats {}", e);
+                        write_message::<Response>(writer, Response::ErrorUnknown {
+                            message: e.to_string()
+                        })?;
+                    }
+                }
+            }
+            Request::Checkpoint { name } => {
+                match Self::checkpoint(engine.as_ref(), checkpoint_dir, &name) {
+                    Ok(path) => {
+                        info!(logger, "checkpoint {} to {}", name, path.display());
+                        write_message::<Response>(writer, Response::OkNoContent)?;
+                    }
+                    Err(e) => {
+                        error!(logger, "checkpoint {} {}", name, e);
                         write_message::<Response>(writer, Response::ErrorUnknown {
                             message: e.to_string()
                         })?;
                     }
                 }
-                write_message::<Response>(writer, Response::OkNoContent)?;
             }
             Request::Remove { key } => {
-                match engine.lock().unwrap().remove(key.clone()) {
+                let key_str = String::from_utf8_lossy(&key);
+                match engine.remove_bytes(&key) {
                     Ok(_) => {
-                        info!(logger, "rm {}", key);
+                        info!(logger, "rm {}", key_str);
                         write_message::<Response>(writer, Response::OkNoContent)?;
                     }
                     Err(e) => {
-                        error!(logger, "rm {} {}", key, e);
+                        error!(logger, "rm {} {}", key_str, e);
                         match e {
                             KvError::KeyNotFound => {
                                 write_message::<Response>(writer, Response::ErrorKeyNotFound)?;
@@ -142,45 +212,79 @@ impl KvServer {
 
         Ok(())
     }
+
+    // Clients only name the checkpoint, it always goes to a new directory within the
+    // configured one. The copy gets the engine marker too, so a server started on it picks the
+    // right engine.
+    fn checkpoint(engine: &dyn KvsEngine, checkpoint_dir: Option<&PathBuf>, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let checkpoint_dir = checkpoint_dir.ok_or("checkpoints are disabled, start the server with --checkpoint-dir")?;
+        let mut components = Path::new(name).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+            return Err(Box::from(format!("invalid checkpoint name {:?}", name)));
+        }
+
+        let path = checkpoint_dir.join(name);
+        engine.checkpoint(&path)?;
+        if let Some(engine_type) = current_engine(current_dir()?)? {
+            set_engine(path.clone(), engine_type)?;
+        }
+        Ok(path)
+    }
+
+    fn write_conditional_response(
+        logger: &Logger,
+        writer: &mut dyn Write,
+        op: &str,
+        key: &str,
+        result: crate::Result<()>) -> Result<(), Box<dyn Error>> {
+        let res = match result {
+            Ok(_) => {
+                info!(logger, "{} {}", op, key);
+                Response::OkNoContent
+            }
+            Err(KvError::ConditionFailed) => {
+                info!(logger, "{} {} condition failed", op, key);
+                Response::ErrorConditionFailed
+            }
+            Err(e) => {
+                error!(logger, "{} {} {}", op, key, e);
+                Response::ErrorUnknown {
+                    message: e.to_string()
+                }
+            }
+        };
+        write_message::<Response>(writer, res)?;
+        Ok(())
+    }
 }
 
-fn new_engine(engine_type: EngineType) -> Result<Box<dyn KvsEngine + Send>, Box<dyn Error>> {
+fn new_engine(engine_type: EngineType, durability: Durability) -> Result<Box<dyn KvsEngine>, Box<dyn Error>> {
     let dir = current_dir()?;
     let curr_engine = current_engine(dir.clone())?;
 
     match engine_type {
         EngineType::KvStore => {
-            if let Some(curr_engine) = curr_engine {
-                match curr_engine {
-                    EngineType::Sled => {
-                        return Err(Box::from("engine mismatch"))
-                    }
-                    _ => {}
-                }
+            if let Some(EngineType::Sled) = curr_engine {
+                return Err(Box::from("engine mismatch"))
             }
 
             set_engine(dir.clone(), engine_type)?;
-            Ok(Box::new(KvStore::open(dir)?))
+            Ok(Box::new(KvStore::open_with(dir, KvStoreOptions::new().durability(durability))?))
         }
         EngineType::Sled => {
-            if let Some(curr_engine) = curr_engine {
-                match curr_engine {
-                    EngineType::KvStore => {
-                        return Err(Box::from("engine mismatch"))
-                    }
-                    _ => {}
-                }
+            if let Some(EngineType::KvStore) = curr_engine {
+                return Err(Box::from("engine mismatch"))
             }
 
             set_engine(dir.clone(), engine_type)?;
-            Ok(Box::new(Sled::open(dir)?))
+            Ok(Box::new(Sled::open_with_durability(dir, durability)?))
         }
         EngineType::Auto => {
             match current_engine(dir)? {
                 Some(engine_type) => {
-                    new_engine(engine_type)
+                    new_engine(engine_type, durability)
                 }
-                None => new_engine(EngineType::KvStore)
+                None => new_engine(EngineType::KvStore, durability)
             }
         }
     }
@@ -1,8 +1,18 @@
 extern crate core;
 
 pub use kv::KvStore;
+pub use snapshot::KvSnapshot;
+pub use options::{KvStoreOptions, RecoveryMode};
+pub use restore::RestorePoint;
+pub use verify::{GenerationReport, Problem, VerifyReport};
+pub use repair::{LostRange, RepairReport};
+pub use durability::{Durability, ParseDurabilityError};
 pub use err::{Result, KvError};
-pub use engine::{KvsEngine, Sled};
+pub use engine::{KeyRange, KvsEngine, ScanIter, ScanOptions, Sled};
+pub use batch::WriteBatch;
+pub use dump::{DumpEntry, DumpIter};
+pub use cache::CacheStats;
+pub use stats::{CompactionStats, EngineStats};
 pub use kv_server::{KvServer};
 pub use kv_client::{KvClient};
 
@@ -16,6 +26,21 @@ mod engine;
 mod message;
 mod net;
 mod log;
+mod record;
+mod hint;
+mod options;
+mod batch;
+mod ttl;
+mod snapshot;
+mod durability;
+mod blob;
+mod cache;
+mod manifest;
+mod restore;
+mod dump;
+mod verify;
+mod repair;
+mod stats;
 
 
 
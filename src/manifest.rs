@@ -0,0 +1,48 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::err::Result;
+
+// The manifest lists the generations that make up the store and the compaction in progress,
+// if any. It is always replaced as a whole, so after a crash `open` can tell the logs it
+// should trust from the ones an interrupted compaction left behind.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub generations: BTreeSet<u64>,
+    pub compaction: Option<CompactionRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactionRecord {
+    pub inputs: Vec<u64>,
+    pub output: u64,
+}
+
+pub fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join("MANIFEST")
+}
+
+// Stores written before the manifest existed have none.
+pub fn read_manifest(dir: &Path) -> Result<Option<Manifest>> {
+    match fs::read(manifest_path(dir)) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp_path = dir.join("MANIFEST.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, manifest)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    fs::rename(tmp_path, manifest_path(dir))?;
+    // The rename itself only survives a crash once the directory is synced.
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
@@ -1,16 +1,25 @@
 use serde::{Serialize, Deserialize};
+use crate::stats::EngineStats;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get { key: Vec<u8> },
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+    SetWithTtl { key: Vec<u8>, value: Vec<u8>, ttl_ms: u64 },
+    CompareAndSwap { key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>> },
+    SetIfAbsent { key: Vec<u8>, value: Vec<u8> },
+    SetIfPresent { key: Vec<u8>, value: Vec<u8> },
+    Checkpoint { name: String },
+    Stats,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    OkValue { value: Option<String> },
+    OkValue { value: Option<Vec<u8>> },
     OkNoContent,
     ErrorKeyNotFound,
-    ErrorUnknown{message: String}
+    ErrorUnknown{message: String},
+    ErrorConditionFailed,
+    OkStats { stats: EngineStats },
 }
\ No newline at end of file
@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::durability::Durability;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryMode {
+    /// Refuse to open when the active generation ends with a torn or corrupted record.
+    Strict,
+    /// Truncate the active generation after its last valid record.
+    #[default]
+    TruncateTail,
+}
+
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) recovery_mode: RecoveryMode,
+    pub(crate) compaction_threshold: u64,
+    pub(crate) compaction_ratio: f64,
+    pub(crate) min_generations: usize,
+    pub(crate) auto_compaction: bool,
+    pub(crate) ttl_reap_interval: Duration,
+    pub(crate) durability: Durability,
+    pub(crate) max_segment_size: u64,
+    pub(crate) blob_threshold: u64,
+    pub(crate) cache_size: u64,
+    pub(crate) read_only: bool,
+    pub(crate) archive_dir: Option<PathBuf>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            recovery_mode: RecoveryMode::default(),
+            compaction_threshold: 1024 * 1024,
+            compaction_ratio: 0.0,
+            min_generations: 1,
+            auto_compaction: true,
+            ttl_reap_interval: Duration::from_secs(1),
+            durability: Durability::default(),
+            max_segment_size: 64 * 1024 * 1024,
+            blob_threshold: 64 * 1024,
+            cache_size: 0,
+            read_only: false,
+            archive_dir: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    pub fn recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.recovery_mode = recovery_mode;
+        self
+    }
+
+    /// Stale bytes that must pile up before compaction starts.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Minimum ratio of stale bytes to live bytes before compaction starts.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Minimum number of log generations on disk before compaction starts.
+    pub fn min_generations(mut self, count: usize) -> Self {
+        self.min_generations = count;
+        self
+    }
+
+    /// When disabled, compaction only runs through `KvStore::compact`.
+    pub fn auto_compaction(mut self, enabled: bool) -> Self {
+        self.auto_compaction = enabled;
+        self
+    }
+
+    /// How often expired keys are looked for and removed from the log.
+    pub fn ttl_reap_interval(mut self, interval: Duration) -> Self {
+        self.ttl_reap_interval = interval;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Size after which the active log segment is sealed and a new generation started.
+    pub fn max_segment_size(mut self, bytes: u64) -> Self {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// Values of at least this many bytes are stored in their own blob file instead of the log,
+    /// so compaction does not copy them.
+    pub fn blob_threshold(mut self, bytes: u64) -> Self {
+        self.blob_threshold = bytes;
+        self
+    }
+
+    /// Memory budget of the value cache, counted in bytes of cached keys and values. The
+    /// cache is off at 0, the default.
+    pub fn cache_size(mut self, bytes: u64) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// Opens the store without ever writing to its directory, so several processes can read it
+    /// at once. Writes fail with `KvError::ReadOnly`, and a torn tail is not repaired. Since it
+    /// never creates the `LOCK` file, it does not keep a writer out of a directory that has none.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Keeps every log segment the store seals in `dir`, along with the blobs its records point
+    /// at, so `KvStore::restore` can replay writes that compaction has dropped since. Nothing is
+    /// ever deleted from it.
+    pub fn archive_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.archive_dir = Some(dir.into());
+        self
+    }
+}
@@ -0,0 +1,349 @@
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use serde_json::Deserializer;
+use crate::cmd::{Command, JsonCommand};
+use crate::err::{KvError, Result};
+use crate::stream::BufReaderWithPos;
+
+// Every binary log starts with the magic bytes followed by the big endian format version.
+// Legacy logs written with serde_json have no header and always start with `{`.
+pub const LOG_MAGIC: [u8; 4] = *b"RKVL";
+pub const LOG_VERSION: u32 = 2;
+pub const LOG_HEADER_SIZE: u64 = 8;
+
+// payload length (u32) + crc32 (u32) + sequence number (u64) + timestamp (u64)
+pub const RECORD_HEADER_SIZE: u64 = 24;
+// Version 1 records have no timestamp.
+const V1_RECORD_HEADER_SIZE: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Json,
+    BinaryV1,
+    Binary,
+}
+
+// `timestamp` is when the record was first written, in milliseconds since the Unix epoch.
+// Records from legacy logs and version 1 logs have none, and neither do the tombstones a merge
+// rewrites.
+pub struct Record {
+    pub seq: u64,
+    pub timestamp: Option<u64>,
+    pub command: Command,
+}
+
+pub struct LogEntry {
+    pub pos: u64,
+    pub size: u64,
+    pub seq: Option<u64>,
+    pub timestamp: Option<u64>,
+    pub command: Command,
+}
+
+// What a damaged log still holds, in log order.
+pub enum Salvaged {
+    Entry(LogEntry),
+    Lost { offset: u64, len: u64 },
+}
+
+pub struct LogReader {
+    format: LogFormat,
+    reader: BufReaderWithPos<File>,
+}
+
+impl LogReader {
+    pub fn open(path: &Path) -> Result<LogReader> {
+        let file = File::open(path)?;
+        let mut reader = BufReaderWithPos::new(file)?;
+        let format = read_log_header(&mut reader)?;
+        Ok(LogReader {
+            format,
+            reader,
+        })
+    }
+
+    pub fn read_command(&mut self, pos: u64, size: u64) -> Result<Command> {
+        Ok(self.read_record(pos, size)?.command)
+    }
+
+    // Legacy JSON records have no sequence number, they get 0.
+    pub fn read_record(&mut self, pos: u64, size: u64) -> Result<Record> {
+        if self.reader.pos != pos {
+            self.reader.seek(SeekFrom::Start(pos))?;
+        }
+
+        let mut cmd_reader = (&mut self.reader).take(size);
+        match self.format {
+            LogFormat::Json => Ok(Record {
+                seq: 0,
+                timestamp: None,
+                command: serde_json::from_reader::<_, JsonCommand>(cmd_reader)?.into(),
+            }),
+            LogFormat::BinaryV1 | LogFormat::Binary => {
+                match read_record(&mut cmd_reader, self.format)? {
+                    Some(record) => Ok(record),
+                    None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                }
+            }
+        }
+    }
+
+    // Length of the longest prefix of the log made of complete, valid records.
+    pub fn valid_len(&mut self) -> Result<u64> {
+        let mut valid_len = match self.format {
+            LogFormat::Json => 0,
+            LogFormat::BinaryV1 | LogFormat::Binary => LOG_HEADER_SIZE,
+        };
+        match self.read_entries(|entry| {
+            valid_len = entry.pos + entry.size;
+            Ok(())
+        }) {
+            Err(e) if !e.is_corruption() => Err(e),
+            _ => Ok(valid_len),
+        }
+    }
+
+    // Visits every record of the log in order. Legacy JSON records carry no sequence number.
+    pub fn read_entries(&mut self, mut visit: impl FnMut(LogEntry) -> Result<()>) -> Result<()> {
+        match self.format {
+            LogFormat::Json => {
+                let mut pos = self.reader.seek(SeekFrom::Start(0))?;
+                let mut cmd_stream = Deserializer::from_reader(&mut self.reader)
+                    .into_iter::<JsonCommand>();
+                while let Some(cmd_res) = cmd_stream.next() {
+                    let new_pos = cmd_stream.byte_offset() as u64;
+                    visit(LogEntry {
+                        pos,
+                        size: new_pos - pos,
+                        seq: None,
+                        timestamp: None,
+                        command: cmd_res?.into(),
+                    })?;
+                    pos = new_pos;
+                }
+            }
+            LogFormat::BinaryV1 | LogFormat::Binary => {
+                let mut pos = self.reader.seek(SeekFrom::Start(LOG_HEADER_SIZE))?;
+                while let Some(record) = read_record(&mut self.reader, self.format)? {
+                    let new_pos = self.reader.pos;
+                    visit(LogEntry {
+                        pos,
+                        size: new_pos - pos,
+                        seq: Some(record.seq),
+                        timestamp: record.timestamp,
+                        command: record.command,
+                    })?;
+                    pos = new_pos;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Reads every record of a damaged log that is still intact. After a bad record, reading picks
+// up again at the next offset holding a valid one, and the bytes in between are lost.
+pub fn salvage_log(path: &Path) -> Result<Vec<Salvaged>> {
+    let data = fs::read(path)?;
+    if data.first() == Some(&b'{') {
+        return salvage_json_log(path, data.len() as u64);
+    }
+
+    // A damaged header is taken for one of the current version.
+    let format = match data.get(..LOG_HEADER_SIZE as usize) {
+        Some(header) if header[..4] == LOG_MAGIC && header[4..] == 1u32.to_be_bytes() => LogFormat::BinaryV1,
+        _ => LogFormat::Binary,
+    };
+    let mut salvaged = Vec::new();
+    let mut lost_from = None;
+    let mut pos = LOG_HEADER_SIZE as usize;
+    while pos < data.len() {
+        match parse_record(&data[pos..], format) {
+            Some((record, size)) => {
+                if let Some(offset) = lost_from.take() {
+                    salvaged.push(Salvaged::Lost { offset, len: pos as u64 - offset });
+                }
+                salvaged.push(Salvaged::Entry(LogEntry {
+                    pos: pos as u64,
+                    size,
+                    seq: Some(record.seq),
+                    timestamp: record.timestamp,
+                    command: record.command,
+                }));
+                pos += size as usize;
+            }
+            None => {
+                lost_from.get_or_insert(pos as u64);
+                pos += 1;
+            }
+        }
+    }
+    if let Some(offset) = lost_from {
+        salvaged.push(Salvaged::Lost { offset, len: data.len() as u64 - offset });
+    }
+    Ok(salvaged)
+}
+
+// Legacy JSON records have no framing to pick up again at, everything after the first bad one
+// is lost.
+fn salvage_json_log(path: &Path, len: u64) -> Result<Vec<Salvaged>> {
+    let mut salvaged = Vec::new();
+    let mut valid_len = 0;
+    let result = LogReader::open(path)?.read_entries(|entry| {
+        valid_len = entry.pos + entry.size;
+        salvaged.push(Salvaged::Entry(entry));
+        Ok(())
+    });
+    match result {
+        Err(e) if !e.is_corruption() => return Err(e),
+        _ => {}
+    }
+    if valid_len < len {
+        salvaged.push(Salvaged::Lost { offset: valid_len, len: len - valid_len });
+    }
+    Ok(salvaged)
+}
+
+// Reads the record at the start of `data`, if there is a valid one, and its size. The payload
+// is decoded before the checksum is computed, since it rejects almost any garbage after a few
+// bytes, which keeps looking for the next record at every offset cheap.
+fn parse_record(data: &[u8], format: LogFormat) -> Option<(Record, u64)> {
+    let header_size = match format {
+        LogFormat::BinaryV1 => V1_RECORD_HEADER_SIZE,
+        _ => RECORD_HEADER_SIZE,
+    } as usize;
+    let header = data.get(..header_size)?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let payload = data.get(header_size..header_size.checked_add(len)?)?;
+    let command = bincode::deserialize(payload).ok()?;
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[8..]);
+    hasher.update(payload);
+    if hasher.finalize() != crc {
+        return None;
+    }
+
+    let mut seq = [0; 8];
+    seq.copy_from_slice(&header[8..16]);
+    let timestamp = match format {
+        LogFormat::BinaryV1 => None,
+        _ => {
+            let mut timestamp = [0; 8];
+            timestamp.copy_from_slice(&header[16..24]);
+            Some(u64::from_be_bytes(timestamp)).filter(|&timestamp| timestamp > 0)
+        }
+    };
+    Some((Record { seq: u64::from_be_bytes(seq), timestamp, command }, (header_size + len) as u64))
+}
+
+pub fn write_log_header(writer: &mut impl Write) -> Result<u64> {
+    writer.write_all(&LOG_MAGIC)?;
+    writer.write_all(&LOG_VERSION.to_be_bytes())?;
+    Ok(LOG_HEADER_SIZE)
+}
+
+fn read_log_header(reader: &mut BufReaderWithPos<File>) -> Result<LogFormat> {
+    let mut header = [0; LOG_HEADER_SIZE as usize];
+    let len = read_full(reader, &mut header)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    if len == 0 || header[0] == b'{' {
+        return Ok(LogFormat::Json);
+    }
+    if len < header.len() {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if header[..4] != LOG_MAGIC {
+        return Err(KvError::CorruptedLogHeader);
+    }
+
+    // Version 1 logs are still read, new records always go to a log of the current version.
+    match u32::from_be_bytes([header[4], header[5], header[6], header[7]]) {
+        1 => Ok(LogFormat::BinaryV1),
+        LOG_VERSION => Ok(LogFormat::Binary),
+        version => Err(KvError::UnsupportedLogVersion(version)),
+    }
+}
+
+// A missing timestamp is written as 0.
+pub fn write_record(writer: &mut impl Write, seq: u64, timestamp: Option<u64>, command: &Command) -> Result<u64> {
+    let payload = bincode::serialize(command)?;
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+
+    let seq_bytes = seq.to_be_bytes();
+    let timestamp_bytes = timestamp.unwrap_or_default().to_be_bytes();
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&seq_bytes);
+    hasher.update(&timestamp_bytes);
+    hasher.update(&payload);
+
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&hasher.finalize().to_be_bytes())?;
+    writer.write_all(&seq_bytes)?;
+    writer.write_all(&timestamp_bytes)?;
+    writer.write_all(&payload)?;
+    Ok(RECORD_HEADER_SIZE + payload.len() as u64)
+}
+
+// Returns `None` on a clean end of log, an `UnexpectedEof` error when the record is cut short.
+pub fn read_record(reader: &mut impl Read, format: LogFormat) -> Result<Option<Record>> {
+    let header_size = match format {
+        LogFormat::BinaryV1 => V1_RECORD_HEADER_SIZE,
+        _ => RECORD_HEADER_SIZE,
+    } as usize;
+    let mut header = [0; RECORD_HEADER_SIZE as usize];
+    match read_full(reader, &mut header[..header_size])? {
+        0 => return Ok(None),
+        len if len < header_size => {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        _ => {}
+    }
+
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    // The timestamp follows the sequence number, both are covered by the checksum.
+    let checked = &header[8..header_size];
+
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(checked);
+    hasher.update(&payload);
+    if hasher.finalize() != crc {
+        return Err(KvError::ChecksumMismatch);
+    }
+
+    let mut seq = [0; 8];
+    seq.copy_from_slice(&header[8..16]);
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(&header[16..24]);
+    Ok(Some(Record {
+        seq: u64::from_be_bytes(seq),
+        timestamp: Some(u64::from_be_bytes(timestamp)).filter(|&timestamp| timestamp > 0),
+        command: bincode::deserialize(&payload)?,
+    }))
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(len)
+}
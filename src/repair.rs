@@ -0,0 +1,43 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+/// What `KvStore::repair` salvaged from a data directory and what it could not.
+#[derive(Debug, Clone, Default)]
+pub struct RepairReport {
+    /// Damaged generations, moved to the `quarantine` subdirectory.
+    pub quarantined: Vec<u64>,
+    /// The new generation holding the salvaged records, `None` when nothing was damaged.
+    pub output: Option<u64>,
+    pub salvaged_records: u64,
+    pub lost: Vec<LostRange>,
+    /// Keys whose latest value is a blob that cannot be read. They are removed, so that an
+    /// older value does not come back in its place.
+    pub lost_blobs: Vec<Vec<u8>>,
+}
+
+impl RepairReport {
+    pub fn is_clean(&self) -> bool {
+        self.quarantined.is_empty()
+    }
+}
+
+/// An unreadable region of a log. Whatever it held was written between the records around it,
+/// so when writes came in key order, like those of a load, the keys it lost sort between
+/// `prev_key` and `next_key`. Either is `None` at the edge of the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostRange {
+    pub gen: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub prev_key: Option<Vec<u8>>,
+    pub next_key: Option<Vec<u8>>,
+}
+
+impl Display for LostRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let key = |key: &Option<Vec<u8>>| key.as_ref()
+            .map_or_else(|| "-".to_owned(), |key| format!("{:?}", String::from_utf8_lossy(key)));
+        write!(f, "gen {}: lost {} bytes at offset {}, written between keys {} and {}",
+            self.gen, self.len, self.offset, key(&self.prev_key), key(&self.next_key))
+    }
+}
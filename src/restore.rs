@@ -0,0 +1,21 @@
+/// Where `KvStore::restore` stops replaying archived writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// Replays every archived write.
+    Latest,
+    /// Replays the writes up to the one with this sequence number, the version it stored.
+    Seq(u64),
+    /// Replays the writes made up to this time, in milliseconds since the Unix epoch.
+    Time(u64),
+}
+
+impl RestorePoint {
+    // Records without a timestamp predate timestamps altogether.
+    pub(crate) fn includes(&self, seq: u64, timestamp: Option<u64>) -> bool {
+        match *self {
+            RestorePoint::Latest => true,
+            RestorePoint::Seq(last) => seq <= last,
+            RestorePoint::Time(last) => timestamp.is_none_or(|timestamp| timestamp <= last),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+use crate::dump::DumpIter;
+use crate::engine::{into_string, prefix_range, KeyRange, ScanIter, ScanOptions};
+use crate::err::Result;
+use crate::kv::{read_key, Index, KvStoreEntries, KvStoreScan, ReaderPool};
+
+/// A read-only view of a `KvStore` as of the moment `KvStore::snapshot` was called.
+///
+/// Compaction keeps every generation the snapshot reads from until the snapshot, and every
+/// scan started from it, is dropped.
+#[derive(Clone)]
+pub struct KvSnapshot {
+    index: Arc<RwLock<Index>>,
+    readers: Arc<ReaderPool>,
+}
+
+impl KvSnapshot {
+    pub(crate) fn new(index: Index, readers: ReaderPool) -> KvSnapshot {
+        KvSnapshot {
+            index: Arc::new(RwLock::new(index)),
+            readers: Arc::new(readers),
+        }
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        read_key(&self.index, &self.readers, key)
+    }
+
+    /// Fails with `KvError::Encode` when the stored value is not UTF-8.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(into_string(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn scan(&self, range: KeyRange, options: ScanOptions) -> Result<ScanIter> {
+        Ok(Box::new(KvStoreScan::new(&self.index, &self.readers, range, options)))
+    }
+
+    pub fn scan_prefix(&self, prefix: Vec<u8>, options: ScanOptions) -> Result<ScanIter> {
+        self.scan(prefix_range(prefix), options)
+    }
+
+    /// Iterates over every pair in key order, along with its expiry deadline.
+    pub fn entries(&self) -> DumpIter {
+        let range = (Bound::Unbounded, Bound::Unbounded);
+        Box::new(KvStoreEntries(KvStoreScan::new(&self.index, &self.readers, range, ScanOptions::new())))
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::cache::CacheStats;
+
+/// Storage statistics of an engine, see `KvsEngine::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Keys with a value that has not expired.
+    pub live_keys: u64,
+    /// Bytes on disk holding the current value of a key, blobs included.
+    pub live_bytes: u64,
+    /// Bytes on disk that compaction can reclaim.
+    pub stale_bytes: u64,
+    /// Log files the store is made of, always 0 for `Sled`.
+    pub generations: u64,
+    /// The last compaction that finished since the store was opened.
+    pub last_compaction: Option<CompactionStats>,
+    pub cache: CacheStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionStats {
+    /// When it finished, in milliseconds since the Unix epoch.
+    pub finished_at: u64,
+    pub duration: Duration,
+}
+
+impl Display for EngineStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "live keys: {}", self.live_keys)?;
+        writeln!(f, "live bytes: {}", self.live_bytes)?;
+        writeln!(f, "stale bytes: {}", self.stale_bytes)?;
+        writeln!(f, "generations: {}", self.generations)?;
+        match &self.last_compaction {
+            Some(compaction) => writeln!(f, "last compaction: finished at {} after {} ms",
+                compaction.finished_at, compaction.duration.as_millis())?,
+            None => writeln!(f, "last compaction: none")?,
+        }
+        write!(f, "cache: {} hits, {} misses, {} bytes", self.cache.hits, self.cache.misses, self.cache.size)
+    }
+}
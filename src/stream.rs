@@ -14,6 +14,10 @@ impl<Writable: Write + Seek> BufWriterWithPos<Writable> {
             pos,
         })
     }
+
+    pub fn get_ref(&self) -> &Writable {
+        self.writer.get_ref()
+    }
 }
 
 impl<Writable: Write + Seek> Write for BufWriterWithPos<Writable> {
@@ -0,0 +1,57 @@
+use std::sync::mpsc;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ::log::warn;
+use crate::err::Result;
+
+// Expiry deadlines are stored as milliseconds since the Unix epoch, so they survive restarts.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn deadline(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+pub fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+// Runs `reap` on a background thread every `interval` until dropped. Dropping waits for the
+// thread, so whatever `reap` holds on to is released by then.
+pub struct Reaper {
+    shutdown: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    pub fn spawn(interval: Duration, mut reap: impl FnMut() -> Result<()> + Send + 'static) -> Reaper {
+        let (shutdown, receiver) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                if let Err(e) = reap() {
+                    warn!("failed to remove expired keys: {}", e);
+                }
+            }
+        });
+
+        Reaper {
+            shutdown: Some(shutdown),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        drop(self.shutdown.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+/// What `KvStore::verify` found in a data directory.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub generations: Vec<GenerationReport>,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Sizes are in bytes of the log file, blobs not included. Every byte of a generation the
+/// manifest does not list is stale.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationReport {
+    pub gen: u64,
+    pub records: u64,
+    pub live_bytes: u64,
+    pub stale_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// A record that fails its checksum or cannot be decoded. Later damage in the same log is
+    /// still reported, but the generation's counts stop at the first bad record.
+    CorruptRecord { gen: u64, offset: u64, message: String },
+    /// A record cut short by the end of the log.
+    TruncatedRecord { gen: u64, offset: u64 },
+    /// A generation the manifest lists but whose log is gone.
+    MissingGeneration { gen: u64 },
+    /// A log the manifest does not list, left over by an interrupted compaction.
+    UnlistedGeneration { gen: u64 },
+    CorruptManifest { message: String },
+    MissingBlob { seq: u64 },
+    CorruptBlob { seq: u64 },
+    /// A file the store would have cleaned up, like the hint of a missing log or a blob no
+    /// record points at.
+    OrphanedFile { name: String },
+    /// A file the store never writes.
+    UnexpectedFile { name: String },
+}
+
+impl Display for GenerationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "gen {}: {} records, {} live bytes, {} stale bytes",
+            self.gen, self.records, self.live_bytes, self.stale_bytes)
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::CorruptRecord { gen, offset, message } => {
+                write!(f, "gen {}: corrupt record at offset {}: {}", gen, offset, message)
+            }
+            Problem::TruncatedRecord { gen, offset } => {
+                write!(f, "gen {}: truncated record at offset {}", gen, offset)
+            }
+            Problem::MissingGeneration { gen } => write!(f, "gen {}: listed in the manifest but missing", gen),
+            Problem::UnlistedGeneration { gen } => write!(f, "gen {}: not listed in the manifest", gen),
+            Problem::CorruptManifest { message } => write!(f, "corrupt manifest: {}", message),
+            Problem::MissingBlob { seq } => write!(f, "blob {}: missing", seq),
+            Problem::CorruptBlob { seq } => write!(f, "blob {}: corrupt", seq),
+            Problem::OrphanedFile { name } => write!(f, "orphaned file {}", name),
+            Problem::UnexpectedFile { name } => write!(f, "unexpected file {}", name),
+        }
+    }
+}
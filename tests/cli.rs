@@ -1,10 +1,11 @@
 use assert_cmd::prelude::*;
+use kvs::{KvsEngine, KvStore, KvStoreOptions};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,42 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["set", "key", "value", "--ttl", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +99,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +131,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +142,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,25 +153,37 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_durability() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--durability", "sometimes", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stdout_path = temp_dir.path().join("stdout");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stdout(File::create(&stdout_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stdout_path).expect("unable to read from stdout file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +198,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +218,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +239,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +260,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +268,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +276,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +284,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +292,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +300,15 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "value4", "--ttl", "1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +316,15 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +337,35 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    // key3 expired while the server was restarting
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +383,256 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Checkpoints should only be written within the server's `--checkpoint-dir`.
+#[test]
+fn cli_checkpoint() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let checkpoint_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--checkpoint-dir"])
+        .arg(checkpoint_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["checkpoint", "copy", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(checkpoint_dir.path().join("copy").join("engine").exists());
+
+    for name in ["../escape", "/tmp/escape", "nested/copy", ""] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["checkpoint", name, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("invalid checkpoint name"));
+    }
+    assert!(!checkpoint_dir.path().join("nested").exists());
+    assert!(!temp_dir.path().join("escape").exists());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs load` should restore what `kvs dump` wrote, into a directory of either engine.
+#[test]
+fn cli_dump_load() {
+    let source_dir = TempDir::new().unwrap();
+    for (key, value) in [("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, value])
+            .current_dir(&source_dir)
+            .assert()
+            .success();
+    }
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&source_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let dump = String::from_utf8(output.stdout).unwrap();
+    assert!(dump.contains(r#""key":"key1""#) && dump.contains(r#""value":"value2""#));
+
+    for engine in ["kvs", "sled"] {
+        let target_dir = TempDir::new().unwrap();
+        assert_cmd::Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["load", "--engine", engine])
+            .write_stdin(dump.clone())
+            .current_dir(&target_dir)
+            .assert()
+            .success()
+            .stderr(contains("Loaded 2 keys"));
+
+        // Without `--engine` the directory's own engine is used.
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["dump"])
+            .current_dir(&target_dir)
+            .assert()
+            .success()
+            .stdout(dump.clone());
+    }
+
+    let sled_dir = TempDir::new().unwrap();
+    fs::write(sled_dir.path().join("engine"), "sled").unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", "--engine", "kvs"])
+        .current_dir(&sled_dir)
+        .assert()
+        .failure()
+        .stderr(contains("engine mismatch"));
+}
+
+// `kvs verify` should exit with a non-zero code and name the damage when a log is corrupt.
+#[test]
+fn cli_verify() {
+    let temp_dir = TempDir::new().unwrap();
+    for (key, value) in [("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(is_empty());
+
+    // Every `kvs set` opens a new generation, damage the record after the header of the first.
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path).unwrap();
+    log[8 + 30] ^= 0xff;
+    fs::write(&log_path, log).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("gen 1: corrupt record at offset 8"))
+        .stderr(contains("1 problems found"));
+}
+
+// `kvs restore` should rebuild a directory from a checkpoint and an archive, up to the write
+// named by `--seq` or the time named by `--time`.
+#[test]
+fn cli_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let checkpoint = backup_dir.path().join("checkpoint");
+    let archive = backup_dir.path().join("archive");
+    let options = KvStoreOptions::new().archive_dir(&archive);
+    let store = KvStore::open_with(temp_dir.path(), options.clone()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.checkpoint(&checkpoint).unwrap();
+    let good_seq = store.set_if_version(b"key2".to_vec(), b"value2".to_vec(), None).unwrap();
+    thread::sleep(Duration::from_millis(5));
+    let good_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    thread::sleep(Duration::from_millis(5));
+    store.set("key1".to_owned(), "value3".to_owned()).unwrap();
+    drop(store);
+    // Opening again seals the last segment, which puts it in the archive.
+    drop(KvStore::open_with(temp_dir.path(), options).unwrap());
+
+    for (name, point, value) in [
+        ("seq", Some(("--seq", good_seq)), "value1"),
+        ("time", Some(("--time", good_time)), "value1"),
+        ("latest", None, "value3"),
+    ] {
+        let target = backup_dir.path().join(name);
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.arg("restore").args([&checkpoint, &archive, &target]);
+        if let Some((flag, point)) = point {
+            cmd.args([flag, &point.to_string()]);
+        }
+        cmd.assert().success();
+
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", "key1"])
+            .current_dir(&target)
+            .assert()
+            .success()
+            .stdout(format!("{}\n", value));
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", "key2"])
+            .current_dir(&target)
+            .assert()
+            .success()
+            .stdout("value2\n");
+    }
+
+    let target = backup_dir.path().join("both");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .args([&checkpoint, &archive, &target])
+        .args(["--seq", "1", "--time", "1"])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .args([&checkpoint, &archive])
+        .assert()
+        .failure();
+    assert!(!target.exists());
+}
+
+// `kvs repair` should print what it quarantined, salvaged and lost.
+#[test]
+fn cli_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..3 {
+        store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Nothing to repair\n");
+
+    // Damage the second record, sets of `keyN` to `valueN` take 54 bytes after the 8 byte header.
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path).unwrap();
+    log[8 + 54 + 30] ^= 0xff;
+    fs::write(&log_path, log).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("quarantined gen 1"))
+        .stdout(contains("salvaged 2 records into gen"))
+        .stdout(contains(r#"gen 1: lost 54 bytes at offset 62, written between keys "key0" and "key2""#));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+}
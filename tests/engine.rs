@@ -0,0 +1,498 @@
+use std::ops::Bound;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use kvs::{Durability, KeyRange, KvError, KvsEngine, KvStore, KvStoreOptions, Result, ScanOptions, Sled, WriteBatch};
+use tempfile::TempDir;
+
+fn collect_keys(engine: &dyn KvsEngine, range: KeyRange, options: ScanOptions) -> Result<Vec<String>> {
+    engine.scan(range, options)?
+        .map(|pair| pair.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect()
+}
+
+fn bound(key: &str) -> Bound<Vec<u8>> {
+    Bound::Included(key.as_bytes().to_vec())
+}
+
+fn excluded(key: &str) -> Bound<Vec<u8>> {
+    Bound::Excluded(key.as_bytes().to_vec())
+}
+
+fn scan(engine: &dyn KvsEngine) -> Result<()> {
+    for key_id in (0..300).rev() {
+        engine.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+    engine.set("other".to_owned(), "value".to_owned())?;
+    engine.remove("key150".to_owned())?;
+
+    let all: Vec<_> = engine.scan((Bound::Unbounded, Bound::Unbounded), ScanOptions::new())?
+        .collect::<Result<_>>()?;
+    assert_eq!(all.len(), 300);
+    assert_eq!(all[0], (b"key000".to_vec(), b"value0".to_vec()));
+    assert_eq!(all[299], (b"other".to_vec(), b"value".to_vec()));
+    assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    let keys = collect_keys(
+        engine,
+        (bound("key148"), excluded("key152")),
+        ScanOptions::new())?;
+    assert_eq!(keys, vec!["key148", "key149", "key151"]);
+
+    let keys = collect_keys(
+        engine,
+        (excluded("key148"), bound("key152")),
+        ScanOptions::new().reverse())?;
+    assert_eq!(keys, vec!["key152", "key151", "key149"]);
+
+    let keys = collect_keys(
+        engine,
+        (Bound::Unbounded, Bound::Unbounded),
+        ScanOptions::new().reverse().limit(2))?;
+    assert_eq!(keys, vec!["other", "key299"]);
+
+    let keys = collect_keys(
+        engine,
+        (bound("key2"), bound("key1")),
+        ScanOptions::new())?;
+    assert!(keys.is_empty());
+
+    let keys: Vec<_> = engine.scan_prefix(b"key1".to_vec(), ScanOptions::new())?
+        .map(|pair| pair.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 99);
+    assert_eq!(keys[0], "key100");
+
+    let keys: Vec<_> = engine.scan_prefix(b"key2".to_vec(), ScanOptions::new().reverse().limit(200))?
+        .map(|pair| pair.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 100);
+    assert_eq!(keys[0], "key299");
+    assert_eq!(keys[99], "key200");
+
+    Ok(())
+}
+
+#[test]
+fn scan_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn scan_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan(&Sled::open(temp_dir.path())?)
+}
+
+fn binary_keys_and_values(engine: &dyn KvsEngine) -> Result<()> {
+    let key = vec![0, 159, 146, 150, 255];
+    let value = vec![255, 0, 1, 2, 0xc3, 0x28];
+    engine.set_bytes(key.clone(), value.clone())?;
+    engine.set_bytes(vec![255, 255], vec![])?;
+    assert_eq!(engine.get_bytes(&key)?, Some(value));
+    assert_eq!(engine.get_bytes(&[255, 255])?, Some(vec![]));
+    assert!(matches!(engine.get(String::from_utf8_lossy(&[255, 255]).into_owned()), Ok(None)));
+
+    engine.set_bytes(b"text".to_vec(), vec![0xc3, 0x28])?;
+    assert!(matches!(engine.get("text".to_owned()), Err(KvError::Encode(_))));
+
+    let keys: Vec<_> = engine.scan_prefix(vec![255], ScanOptions::new())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![vec![255, 255]]);
+
+    engine.remove_bytes(&key)?;
+    assert_eq!(engine.get_bytes(&key)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(&KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&[255, 255])?, Some(vec![]));
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(&Sled::open(temp_dir.path())?)
+}
+
+fn write_batch(engine: &dyn KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"value3".to_vec());
+    batch.remove(b"key2".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.remove(b"key3".to_vec());
+    batch.set(b"key4".to_vec(), b"value4".to_vec());
+    batch.remove(b"missing".to_vec());
+    assert_eq!(batch.len(), 6);
+    engine.write_batch(batch)?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert_eq!(engine.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    engine.write_batch(WriteBatch::new())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn write_batch_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch(&KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn write_batch_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch(&Sled::open(temp_dir.path())?)
+}
+
+fn expire_keys(engine: &dyn KvsEngine) -> Result<()> {
+    engine.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(500))?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_secs(3600))?;
+    engine.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::from_millis(100))?;
+    engine.set("key3".to_owned(), "value4".to_owned())?;
+
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert!(matches!(engine.remove("key1".to_owned()), Err(KvError::KeyNotFound)));
+
+    let keys: Vec<_> = engine.scan_prefix(b"key".to_vec(), ScanOptions::new())?
+        .map(|pair| pair.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["key2", "key3"]);
+
+    Ok(())
+}
+
+#[test]
+fn expire_keys_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expire_keys(&KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn expire_keys_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expire_keys(&Sled::open(temp_dir.path())?)?;
+
+    let db = reopen_sled(temp_dir.path(), Durability::default())?;
+    assert_eq!(db.get("key1".to_owned())?, None);
+    assert_eq!(db.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn conditional_writes(engine: &dyn KvsEngine) -> Result<()> {
+    engine.set_if_absent("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(engine.set_if_absent("key1".to_owned(), "value2".to_owned()), Err(KvError::ConditionFailed)));
+    assert!(matches!(engine.set_if_present("key2".to_owned(), "value2".to_owned()), Err(KvError::ConditionFailed)));
+    engine.set_if_present("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    let result = engine.compare_and_swap("key1".to_owned(), Some("value1".to_owned()), Some("value3".to_owned()));
+    assert!(matches!(result, Err(KvError::ConditionFailed)));
+    engine.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), Some("value3".to_owned()))?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    engine.compare_and_swap("key1".to_owned(), Some("value3".to_owned()), None)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.compare_and_swap("key1".to_owned(), None, Some("value4".to_owned()))?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value4".to_owned()));
+    engine.compare_and_swap("key2".to_owned(), None, None)?;
+
+    engine.set_with_ttl("key3".to_owned(), "value5".to_owned(), Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(100));
+    engine.set_if_absent("key3".to_owned(), "value6".to_owned())?;
+    assert_eq!(engine.get("key3".to_owned())?, Some("value6".to_owned()));
+
+    // Concurrent read-modify-write cycles must not lose updates.
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..50 {
+                    loop {
+                        let current = engine.get("counter".to_owned()).unwrap().unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        match engine.compare_and_swap("counter".to_owned(), Some(current), Some(next)) {
+                            Ok(()) => break,
+                            Err(KvError::ConditionFailed) => continue,
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(engine.get("counter".to_owned())?, Some("200".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn conditional_writes_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    conditional_writes(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn conditional_writes_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    conditional_writes(&Sled::open(temp_dir.path())?)
+}
+
+const DURABILITIES: [&str; 5] = ["buffered", "flush", "fsync", "group-commit", "group-commit:5"];
+
+#[test]
+fn parse_durability() {
+    for durability in DURABILITIES {
+        assert_eq!(durability.parse::<Durability>().unwrap().to_string().split(':').next(), durability.split(':').next());
+    }
+    assert_eq!("group-commit:5".parse::<Durability>().unwrap(), Durability::GroupCommit { window: Duration::from_millis(5) });
+    assert!("sometimes".parse::<Durability>().is_err());
+    assert!("group-commit:soon".parse::<Durability>().is_err());
+}
+
+// Writes from concurrent writers should all be readable right away and after reopening.
+fn durable_writes(open: impl Fn(Durability) -> Result<Box<dyn KvsEngine>>) -> Result<()> {
+    for durability in DURABILITIES {
+        let engine = open(durability.parse().unwrap())?;
+        thread::scope(|scope| {
+            for thread_id in 0..4 {
+                let engine = &engine;
+                scope.spawn(move || {
+                    for key_id in 0..25 {
+                        let key = format!("{}-key{}-{}", durability, thread_id, key_id);
+                        engine.set(key.clone(), key.clone()).unwrap();
+                        assert_eq!(engine.get(key.clone()).unwrap(), Some(key));
+                    }
+                });
+            }
+        });
+        drop(engine);
+
+        let engine = open(Durability::default())?;
+        let keys: Vec<_> = engine.scan_prefix(format!("{}-", durability).into_bytes(), ScanOptions::new())?
+            .collect::<Result<_>>()?;
+        assert_eq!(keys.len(), 100);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn durable_writes_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    durable_writes(|durability| {
+        let options = KvStoreOptions::new().durability(durability);
+        Ok(Box::new(KvStore::open_with(temp_dir.path(), options)?))
+    })
+}
+
+#[test]
+fn durable_writes_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    durable_writes(|durability| Ok(Box::new(reopen_sled(temp_dir.path(), durability)?)))
+}
+
+// Sled lets go of its directory lock from a background thread, a little while after the last
+// handle is dropped, so reopening right away can fail.
+fn reopen_sled(dir: &Path, durability: Durability) -> Result<Sled> {
+    for _ in 0..50 {
+        match Sled::open_with_durability(dir, durability) {
+            Err(KvError::Sled(e)) if e.to_string().contains("could not acquire lock") => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            result => return result,
+        }
+    }
+    Sled::open_with_durability(dir, durability)
+}
+
+// A checkpoint should hold exactly the writes made before it and open as a store of its own.
+fn checkpoint(engine: &dyn KvsEngine, target: &Path, open: impl Fn(&Path) -> Result<Box<dyn KvsEngine>>) -> Result<()> {
+    for key_id in 0..200 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id).repeat(key_id % 7 + 1))?;
+    }
+    engine.remove("key3".to_owned())?;
+    engine.checkpoint(target)?;
+
+    engine.set("key0".to_owned(), "changed".to_owned())?;
+    engine.set("key200".to_owned(), "value200".to_owned())?;
+    assert!(engine.checkpoint(target).is_err());
+
+    let copy = open(target)?;
+    assert_eq!(copy.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(copy.get("key3".to_owned())?, None);
+    assert_eq!(copy.get("key199".to_owned())?, Some("value199".repeat(4)));
+    assert_eq!(copy.get("key200".to_owned())?, None);
+    assert_eq!(copy.scan((Bound::Unbounded, Bound::Unbounded), ScanOptions::new())?.count(), 199);
+
+    // The copy is independent of the store it was taken from.
+    copy.set("key1".to_owned(), "copy".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".repeat(2)));
+    assert_eq!(engine.get("key0".to_owned())?, Some("changed".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn checkpoint_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Small segments and blobs, so the checkpoint links sealed generations and blob files.
+    let options = KvStoreOptions::new().max_segment_size(1024).blob_threshold(40);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    checkpoint(&store, &target_dir.path().join("copy"), |path| Ok(Box::new(KvStore::open(path)?)))
+}
+
+#[test]
+fn checkpoint_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = Sled::open(temp_dir.path())?;
+    checkpoint(&engine, &target_dir.path().join("copy"), |path| Ok(Box::new(Sled::open(path)?)))
+}
+
+// A dump should carry binary pairs and TTLs over to any engine, and point at bad lines.
+fn dump_and_load(source: &dyn KvsEngine, target: &dyn KvsEngine) -> Result<()> {
+    source.set("key1".to_owned(), "value1".to_owned())?;
+    source.set_bytes(vec![0, 159], vec![255, 0])?;
+    source.set_with_ttl("ttl".to_owned(), "value".to_owned(), Duration::from_secs(3600))?;
+    source.set_with_ttl("expired".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    let mut dump = Vec::new();
+    assert_eq!(source.dump(&mut dump)?, 3);
+    let dump = String::from_utf8(dump).unwrap();
+    let lines: Vec<_> = dump.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], r#"{"key":"AJ8=","value":"/wA=","encoding":"base64"}"#);
+    assert_eq!(lines[1], r#"{"key":"key1","value":"value1"}"#);
+    assert!(lines[2].starts_with(r#"{"key":"ttl","value":"value","expires_at":"#));
+
+    assert_eq!(target.load(&mut dump.as_bytes())?, 3);
+    assert_eq!(target.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(target.get_bytes(&[0, 159])?, Some(vec![255, 0]));
+    assert_eq!(target.get("ttl".to_owned())?, Some("value".to_owned()));
+    assert_eq!(target.get("expired".to_owned())?, None);
+    let mut reloaded = Vec::new();
+    target.dump(&mut reloaded)?;
+    assert_eq!(String::from_utf8(reloaded).unwrap().lines().count(), 3);
+
+    // Expired pairs are skipped on load too.
+    let expired = r#"{"key":"old","value":"value","expires_at":1}"#;
+    assert_eq!(target.load(&mut expired.as_bytes())?, 0);
+    assert_eq!(target.get("old".to_owned())?, None);
+
+    let invalid = "{\"key\":\"key2\",\"value\":\"value2\"}\n\n{\"key\":\"key3\",\"value\":\"!\",\"encoding\":\"base64\"}\n";
+    assert!(matches!(target.load(&mut invalid.as_bytes()), Err(KvError::InvalidDump { line: 3, .. })));
+
+    Ok(())
+}
+
+#[test]
+fn dump_and_load_kvs() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    dump_and_load(&KvStore::open(source_dir.path())?, &Sled::open(target_dir.path())?)
+}
+
+#[test]
+fn dump_and_load_sled() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    dump_and_load(&Sled::open(source_dir.path())?, &KvStore::open(target_dir.path())?)
+}
+
+fn stats(engine: &dyn KvsEngine) -> Result<()> {
+    for key_id in 0..100 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    engine.set_with_ttl("expired".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    let stats = engine.stats()?;
+    assert_eq!(stats.live_keys, 100);
+    assert!(stats.live_bytes > 0);
+
+    for key_id in 0..50 {
+        engine.remove(format!("key{}", key_id))?;
+    }
+    let removed = engine.stats()?;
+    assert_eq!(removed.live_keys, 50);
+    assert!(removed.live_bytes < stats.live_bytes);
+
+    Ok(())
+}
+
+#[test]
+fn stats_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().cache_size(1024))?;
+    stats(&store)?;
+    store.get("key50".to_owned())?;
+    store.get("key50".to_owned())?;
+
+    let stats = store.stats()?;
+    assert!(stats.stale_bytes > 0);
+    assert_eq!(stats.generations, 1);
+    assert_eq!(stats.last_compaction, None);
+    assert_eq!((stats.cache.hits, stats.cache.misses), (1, 1));
+
+    store.compact()?;
+    let compacted = store.stats()?;
+    assert!(compacted.last_compaction.is_some());
+    assert!(compacted.stale_bytes < stats.stale_bytes);
+    assert_eq!(compacted.live_bytes, stats.live_bytes);
+    drop(store);
+
+    // A read-only store counts what is on disk.
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    let read_only = store.stats()?;
+    assert_eq!(read_only.live_keys, 50);
+    assert_eq!(read_only.live_bytes, compacted.live_bytes);
+    assert_eq!(read_only.stale_bytes, compacted.stale_bytes);
+
+    Ok(())
+}
+
+#[test]
+fn stats_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = Sled::open(temp_dir.path())?;
+    stats(&engine)?;
+    let stats = engine.stats()?;
+    assert_eq!(stats.generations, 0);
+    assert_eq!(stats.last_compaction, None);
+
+    Ok(())
+}
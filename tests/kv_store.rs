@@ -1,4 +1,7 @@
-use kvs::{KvsEngine, KvStore, Result};
+use std::{fs, thread};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use kvs::{KvError, KvsEngine, KvStore, KvStoreOptions, LostRange, Problem, RecoveryMode, RestorePoint, Result, ScanOptions, WriteBatch};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +9,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +19,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +30,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +39,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +51,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +67,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +75,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,17 +87,17 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
+    // Compaction runs in the background, so files may vanish while the directory is walked.
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
+        entries
+            .flat_map(|res| {
                 res.and_then(|entry| entry.metadata())
                     .map(|metadata| metadata.len())
             })
-            .sum();
-        len.expect("fail to get directory size")
+            .sum::<u64>()
     };
 
     let mut current_size = dir_size();
@@ -114,7 +117,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +127,911 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should keep reading logs written in the legacy JSON format
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should keep reading binary logs written before records had a timestamp
+#[test]
+fn open_version_1_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = b"RKVL".to_vec();
+    log.extend(1u32.to_be_bytes());
+    for (seq, key, value) in [(0u64, "key1", "value1"), (1, "key2", "value2")] {
+        // bincode of `Command::Set`: the variant index, then both vectors with their length.
+        let mut payload = 0u32.to_le_bytes().to_vec();
+        for bytes in [key.as_bytes(), value.as_bytes()] {
+            payload.extend((bytes.len() as u64).to_le_bytes());
+            payload.extend(bytes);
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&seq.to_be_bytes());
+        hasher.update(&payload);
+
+        log.extend((payload.len() as u32).to_be_bytes());
+        log.extend(hasher.finalize().to_be_bytes());
+        log.extend(seq.to_be_bytes());
+        log.extend(payload);
+    }
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_with_version(b"key2")?, Some((b"value2".to_vec(), 1)));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should refuse to read a record that fails the checksum
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    fs::write(&log_path, content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.get("key1".to_owned()), Err(KvError::ChecksumMismatch)));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("1.hint"))?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::ChecksumMismatch)));
+
+    Ok(())
+}
+
+// Should drop a half-written record at the end of the active generation
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    fs::OpenOptions::new().write(true).open(&log_path)?.set_len(len - 3)?;
+
+    assert!(KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Strict).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Strict)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A batch whose commit marker never reached the log must be dropped as a whole on open.
+#[test]
+fn drop_uncommitted_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"value2".to_vec());
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // The commit marker is the last record: a 24 byte header and a 4 byte payload.
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    fs::OpenOptions::new().write(true).open(&log_path)?.set_len(len - 28)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.remove(b"key1".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Expired keys should get a tombstone in the log without anyone reading them.
+#[test]
+fn reap_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().ttl_reap_interval(Duration::from_millis(20));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(50))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    thread::sleep(Duration::from_millis(300));
+    assert!(fs::metadata(&log_path)?.len() > len);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Versions should only grow, across restarts and compactions that drop the latest records.
+#[test]
+fn versioned_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(false);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let v1 = store.set_if_version(b"key1".to_vec(), b"value1".to_vec(), None)?;
+    assert!(matches!(store.set_if_version(b"key1".to_vec(), b"value2".to_vec(), None), Err(KvError::ConditionFailed)));
+    assert_eq!(store.get_with_version(b"key1")?, Some((b"value1".to_vec(), v1)));
+
+    let v2 = store.set_if_version(b"key1".to_vec(), b"value2".to_vec(), Some(v1))?;
+    assert!(v2 > v1);
+    assert!(matches!(store.set_if_version(b"key1".to_vec(), b"value3".to_vec(), Some(v1)), Err(KvError::ConditionFailed)));
+    assert_eq!(store.get_with_version(b"key1")?, Some((b"value2".to_vec(), v2)));
+
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    let (_, v3) = store.get_with_version(b"key2")?.unwrap();
+    assert!(matches!(store.remove_if_version(b"key2", v2), Err(KvError::ConditionFailed)));
+    store.remove_if_version(b"key2", v3)?;
+    assert_eq!(store.get_with_version(b"key2")?, None);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get_with_version(b"key1")?, Some((b"value2".to_vec(), v2)));
+
+    // The records holding the highest versions are compacted away.
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get_with_version(b"key1")?, Some((b"value2".to_vec(), v2)));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (_, v4) = store.get_with_version(b"key2")?.unwrap();
+    assert!(v4 > v3);
+
+    Ok(())
+}
+
+// Reads and writes should keep seeing the latest values while compaction runs in the background.
+#[test]
+fn read_write_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..300 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            store.set(key, format!("{}-{}", iter, "x".repeat(100)))?;
+        }
+        if iter % 2 == 1 {
+            store.remove("key0".to_owned())?;
+        }
+
+        for key_id in 1..100 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}-{}", iter, "x".repeat(100))));
+        }
+        assert_eq!(store.get("key0".to_owned())?.is_none(), iter % 2 == 1);
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key)?, Some(format!("{}-{}", 299, "x".repeat(100))));
+    }
+
+    Ok(())
+}
+
+// Clones of a store should be usable from many threads at once.
+#[test]
+fn concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..200 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.set(key.clone(), format!("value{}", key_id))?;
+                    assert_eq!(store.get(key)?, Some(format!("value{}", key_id)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..200 {
+            let key = format!("key{}-{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some(format!("value{}", key_id)));
+        }
+    }
+
+    Ok(())
+}
+
+// Sealed generations should be loaded from their hint files instead of being replayed.
+#[test]
+fn load_from_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("1.hint").exists());
+
+    // Replaying the log would fail on the damaged record, the hint file does not need it.
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    content[20] ^= 0xff;
+    fs::write(&log_path, content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // A hint file that does not match its log is ignored.
+    fs::write(temp_dir.path().join("2.hint"), "garbage")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+fn log_count(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count()
+}
+
+// Compaction should only run when triggered explicitly once automatic compaction is disabled.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .auto_compaction(false);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    assert_eq!(log_count(temp_dir.path()), 1);
+
+    store.compact()?;
+    assert_eq!(log_count(temp_dir.path()), 2);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value99".to_owned()));
+    }
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A snapshot should keep its view, and the generations it reads from, across writes and compactions.
+#[test]
+fn snapshot_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(false);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value1".to_owned())?;
+    }
+    store.remove("key9".to_owned())?;
+
+    let snapshot = store.snapshot();
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value2".to_owned())?;
+    }
+    store.remove("key1".to_owned())?;
+    store.set("key10".to_owned(), "value2".to_owned())?;
+
+    // The pinned generation is only moved aside until nothing reads from it anymore.
+    store.compact()?;
+    assert_eq!(log_count(temp_dir.path()), 2);
+    assert!(temp_dir.path().join("1.log.retired").exists());
+
+    assert_eq!(snapshot.get("key0".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key9".to_owned())?, None);
+    assert_eq!(snapshot.get("key10".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // A scan keeps the generations pinned after the snapshot itself is gone.
+    let scan = snapshot.scan_prefix(b"key".to_vec(), ScanOptions::new())?;
+    drop(snapshot);
+    assert!(temp_dir.path().join("1.log.retired").exists());
+    let values: Vec<_> = scan.map(|pair| pair.map(|(_, value)| value)).collect::<Result<_>>()?;
+    assert_eq!(values, vec![b"value1".to_vec(); 9]);
+    assert!(!temp_dir.path().join("1.log.retired").exists());
+    assert_eq!(log_count(temp_dir.path()), 2);
+
+    Ok(())
+}
+
+// Generations a snapshot kept alive must not come back when the process stops before it is dropped.
+#[test]
+fn drop_generations_left_by_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(false);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    std::mem::forget(store.snapshot());
+    store.compact()?;
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    // The last compacted generation, the empty one active before the restart and the new one.
+    assert_eq!(log_count(temp_dir.path()), 3);
+
+    Ok(())
+}
+
+// Compaction should wait until both the stale bytes threshold and the stale-to-live ratio are met.
+#[test]
+fn compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .compaction_ratio(4.0);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    // Roughly as many stale bytes as live bytes: over the threshold but under the ratio.
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(log_count(temp_dir.path()), 1);
+
+    for _ in 0..4 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    Ok(())
+}
+
+// The active segment should be sealed read-only once it passes the size limit.
+#[test]
+fn segment_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(1024)
+        .auto_compaction(false);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(log_count(temp_dir.path()) > 5);
+    assert!(fs::metadata(temp_dir.path().join("1.log"))?.permissions().readonly());
+    assert!(temp_dir.path().join("1.hint").exists());
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
+
+// Compaction should merge the mostly stale segments and leave the others alone, without
+// bringing back keys removed in a merged segment.
+#[test]
+fn merge_stale_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions::new()
+        .max_segment_size(1024)
+        .compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.remove("removed".to_owned())?;
+    for iter in 0..1000 {
+        store.set("hot".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(log_count(temp_dir.path()) < 10);
+    assert!(temp_dir.path().join("1.log").exists());
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("removed".to_owned())?, None);
+    assert_eq!(store.get("hot".to_owned())?, Some("value999".to_owned()));
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Large values should live in blob files that are deleted once compaction drops their records.
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .blob_threshold(1024)
+        .auto_compaction(false);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let large = |fill: u8| vec![fill; 10 * 1024];
+    store.set_bytes(b"small".to_vec(), b"value".to_vec())?;
+    store.set_bytes(b"large1".to_vec(), large(1))?;
+    store.set_bytes(b"large2".to_vec(), large(2))?;
+    assert_eq!(blob_count(temp_dir.path()), 2);
+    assert!(fs::metadata(temp_dir.path().join("1.log"))?.len() < 1024);
+
+    let snapshot = store.snapshot();
+    store.set_bytes(b"large1".to_vec(), large(3))?;
+    store.remove_bytes(b"large2")?;
+    store.compact()?;
+    assert_eq!(blob_count(temp_dir.path()), 3);
+    assert_eq!(snapshot.get_bytes(b"large2")?, Some(large(2)));
+    drop(snapshot);
+    assert_eq!(blob_count(temp_dir.path()), 1);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get_bytes(b"small")?, Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes(b"large1")?, Some(large(3)));
+    assert_eq!(store.get_bytes(b"large2")?, None);
+    let values: Vec<_> = store.scan_prefix(b"large".to_vec(), ScanOptions::new())?.collect::<Result<_>>()?;
+    assert_eq!(values, vec![(b"large1".to_vec(), large(3))]);
+
+    Ok(())
+}
+
+// A failed compaction should not keep the blobs it copied records of alive.
+#[test]
+fn release_blobs_of_failed_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .blob_threshold(1024)
+        .auto_compaction(false);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_bytes(b"a".to_vec(), vec![1; 10 * 1024])?;
+    store.set_bytes(b"b".to_vec(), b"value".to_vec())?;
+    drop(store);
+
+    // Compaction copies keys in order, so it fails on `b` after copying the record of `a`.
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let last = log.len() - 1;
+    log[last] ^= 0xff;
+    fs::write(&log_path, &log)?;
+    assert!(matches!(store.compact(), Err(KvError::ChecksumMismatch)));
+    assert!(!temp_dir.path().join("3.log").exists());
+
+    log[last] ^= 0xff;
+    fs::write(&log_path, &log)?;
+    store.set_bytes(b"a".to_vec(), b"small".to_vec())?;
+    store.compact()?;
+    assert_eq!(blob_count(temp_dir.path()), 0);
+    assert_eq!(store.get_bytes(b"b")?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+fn blob_count(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("blob".as_ref()))
+        .count()
+}
+
+// Repeated reads should be served by the value cache, which must never return an overwritten value.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().cache_size(64);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..10 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (9, 1));
+    assert_eq!(stats.size, 10);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.cache_stats().size, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Values over the budget push out the least recently used ones.
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "0123456789".to_owned())?;
+        store.get(format!("key{}", key_id))?;
+    }
+    assert!(store.cache_stats().size <= 64);
+
+    Ok(())
+}
+
+// Only one store may write to a directory, while read-only stores can share it.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = KvStoreOptions::new().read_only(true);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::Locked)));
+    assert!(matches!(KvStore::open_with(temp_dir.path(), read_only.clone()), Err(KvError::Locked)));
+    drop(store);
+
+    let reader1 = KvStore::open_with(temp_dir.path(), read_only.clone())?;
+    let reader2 = KvStore::open_with(temp_dir.path(), read_only)?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::Locked)));
+    assert_eq!(reader1.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader2.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(reader1.set("key2".to_owned(), "value2".to_owned()), Err(KvError::ReadOnly)));
+    assert!(matches!(reader1.compact(), Err(KvError::ReadOnly)));
+    assert_eq!(log_count(temp_dir.path()), 1);
+    drop(reader1);
+    drop(reader2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A read-only store should read up to a torn tail without writing anything to the directory.
+#[test]
+fn open_torn_log_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    fs::OpenOptions::new().write(true).open(&log_path)?.set_len(len - 3)?;
+    // A crash leaves neither the hint of the active generation nor, on a fresh copy, a LOCK file.
+    fs::remove_file(temp_dir.path().join("1.hint"))?;
+    fs::remove_file(temp_dir.path().join("LOCK"))?;
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+    assert_eq!(fs::metadata(&log_path)?.len(), len - 3);
+    assert!(!temp_dir.path().join("1.hint").exists());
+    assert!(!temp_dir.path().join("LOCK").exists());
+
+    Ok(())
+}
+
+// A compaction that finished writing its output before a crash should be rolled forward.
+#[test]
+fn roll_forward_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let log = fs::read(temp_dir.path().join("1.log"))?;
+    let hint = fs::read(temp_dir.path().join("1.hint"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    // Put back an input generation as if the compaction had not been committed yet.
+    fs::write(temp_dir.path().join("1.log"), log)?;
+    fs::write(temp_dir.path().join("1.hint"), hint)?;
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let mut manifest: serde_json::Value = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+    manifest["generations"].as_array_mut().unwrap().insert(0, 1.into());
+    manifest["compaction"] = serde_json::json!({ "inputs": [1, 2], "output": 3 });
+    fs::write(&manifest_path, serde_json::to_vec(&manifest)?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    Ok(())
+}
+
+// A compaction cut short by a crash should be rolled back, and stray logs ignored.
+#[test]
+fn roll_back_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let mut manifest: serde_json::Value = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+    manifest["compaction"] = serde_json::json!({ "inputs": [1], "output": 7 });
+    fs::write(&manifest_path, serde_json::to_vec(&manifest)?)?;
+    fs::write(temp_dir.path().join("7.log"), "partial")?;
+    fs::write(temp_dir.path().join("9.log"), "stray")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(temp_dir.path().join("1.log").exists());
+    assert!(!temp_dir.path().join("7.log").exists());
+    assert!(!temp_dir.path().join("9.log").exists());
+
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+// A checkpoint plus the archived segments should bring back the store as it was at any
+// sequence number or time since the checkpoint, even after compaction dropped the history.
+#[test]
+fn point_in_time_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint = backup_dir.path().join("checkpoint");
+    let archive = backup_dir.path().join("archive");
+    let options = KvStoreOptions::new()
+        .max_segment_size(1024)
+        .blob_threshold(100)
+        .archive_dir(&archive);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "value1".to_owned())?;
+    }
+    store.checkpoint(&checkpoint)?;
+
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("value2-{}", "x".repeat(key_id * 10)))?;
+    }
+    store.remove("key1".to_owned())?;
+    let last_good = store.set_if_version(b"marker".to_vec(), b"good".to_vec(), None)?;
+    thread::sleep(Duration::from_millis(5));
+    let good_time = now_millis();
+    thread::sleep(Duration::from_millis(5));
+
+    let mut batch = WriteBatch::new();
+    for key_id in 0..20 {
+        batch.set(format!("key{}", key_id).into_bytes(), b"bad".to_vec());
+    }
+    store.write_batch(batch)?;
+    store.compact()?;
+    drop(store);
+    // Opening again seals the last segment, which puts it in the archive.
+    drop(KvStore::open_with(temp_dir.path(), options)?);
+
+    for (name, point) in [("seq", RestorePoint::Seq(last_good)), ("time", RestorePoint::Time(good_time))] {
+        let target = backup_dir.path().join(name);
+        KvStore::restore(&checkpoint, &archive, &target, point)?;
+        let store = KvStore::open(&target)?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value2-".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key19".to_owned())?, Some(format!("value2-{}", "x".repeat(190))));
+        assert_eq!(store.get_with_version(b"marker")?, Some((b"good".to_vec(), last_good)));
+
+        store.set("key0".to_owned(), "value3".to_owned())?;
+        assert!(store.get_with_version(b"key0")?.unwrap().1 > last_good);
+    }
+
+    let target = backup_dir.path().join("latest");
+    KvStore::restore(&checkpoint, &archive, &target, RestorePoint::Latest)?;
+    let store = KvStore::open(&target)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("bad".to_owned()));
+    assert_eq!(store.get("key19".to_owned())?, Some("bad".to_owned()));
+    drop(store);
+
+    // The target must be empty.
+    assert!(KvStore::restore(&checkpoint, &archive, &target, RestorePoint::Latest).is_err());
+
+    Ok(())
+}
+
+// Verify should find every kind of damage without stopping at the first one.
+#[test]
+fn verify_data_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().blob_threshold(100);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("blob".to_owned(), "x".repeat(100))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(matches!(KvStore::verify(temp_dir.path()), Err(KvError::Locked)));
+    drop(store);
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.generations.len(), 2);
+    assert_eq!(report.generations[0].records, 2);
+    assert!(report.generations[0].stale_bytes > 0);
+    assert_eq!(report.generations[1].stale_bytes, 0);
+
+    // Damage the second record of the first generation and cut the last one short. Sets of
+    // `keyN` to `valueN` take 54 bytes.
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    log[8 + 54 + 30] ^= 0xff;
+    fs::write(&log_path, log)?;
+    let log_path = temp_dir.path().join("2.log");
+    let len = fs::metadata(&log_path)?.len();
+    fs::OpenOptions::new().write(true).open(&log_path)?.set_len(len - 1)?;
+    fs::remove_file(temp_dir.path().join("2.blob"))?;
+    fs::write(temp_dir.path().join("99.blob"), "orphan")?;
+    fs::write(temp_dir.path().join("notes.txt"), "stray")?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_ok());
+    assert_eq!(report.problems, vec![
+        Problem::CorruptRecord { gen: 1, offset: 8 + 54, message: "Checksum mismatch".to_owned() },
+        Problem::TruncatedRecord { gen: 2, offset: len - 54 },
+        Problem::MissingBlob { seq: 2 },
+        Problem::OrphanedFile { name: "99.blob".to_owned() },
+        Problem::UnexpectedFile { name: "notes.txt".to_owned() },
+    ]);
+
+    Ok(())
+}
+
+// Verify should report every damaged record of a log, not only the first one.
+#[test]
+fn verify_every_damaged_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // Damage the second and the fourth record.
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    log[8 + 54 + 30] ^= 0xff;
+    log[8 + 54 * 3 + 30] ^= 0xff;
+    fs::write(&log_path, log)?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(report.problems, vec![
+        Problem::CorruptRecord { gen: 1, offset: 8 + 54, message: "Checksum mismatch".to_owned() },
+        Problem::CorruptRecord { gen: 1, offset: 8 + 54 * 3, message: "54 unreadable bytes".to_owned() },
+    ]);
+    assert_eq!(report.generations[0].records, 1);
+
+    Ok(())
+}
+
+// Repair should salvage the records around a damaged one and quarantine the original log.
+#[test]
+fn repair_damaged_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    // Damage the third record of the first generation, reading picks up again at the fourth.
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    log[8 + 54 * 2 + 30] ^= 0xff;
+    fs::write(&log_path, log)?;
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.quarantined, vec![1]);
+    assert_eq!(report.output, Some(3));
+    assert_eq!(report.salvaged_records, 4);
+    assert_eq!(report.lost, vec![LostRange {
+        gen: 1,
+        offset: 8 + 54 * 2,
+        len: 54,
+        prev_key: Some(b"key1".to_vec()),
+        next_key: Some(b"key3".to_vec()),
+    }]);
+    assert!(!log_path.exists());
+    assert!(temp_dir.path().join("quarantine").join("1.log").exists());
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(KvStore::repair(temp_dir.path())?.is_clean());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    for i in [0, 1, 3, 4, 5] {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}